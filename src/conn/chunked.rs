@@ -0,0 +1,188 @@
+use std::{future::Future, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use gmod::*;
+use sqlx::mysql::MySqlRow;
+use tokio::sync::oneshot;
+
+use crate::{
+    query::{
+        param::Param, placeholder::has_order_by, process::process_rows, Query, QueryResult,
+        QueryType,
+    },
+    run_async,
+};
+
+use super::{internal_query, positive_integer, Conn};
+
+pub(super) fn new(l: lua::State, conn: Arc<Conn>) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+
+    let query_str = l.check_string(2)?;
+    conn.check_query_length(&query_str)?;
+    let query_str = query_str
+        .trim_end()
+        .trim_end_matches(';')
+        .to_string();
+    // chunks are separate queries, without a fixed order rows can move between them
+    if !has_order_by(&query_str) {
+        bail!("FetchChunked needs a query with an ORDER BY, otherwise rows can be skipped or repeated between chunks");
+    }
+    let chunk_size = positive_integer("chunk_size", l.check_number(3)?)?;
+    l.check_function(4)?;
+
+    let mut query = Query::new(query_str, QueryType::FetchAll);
+    query.parse_callback_or_options(l, 5)?;
+    if query.sync {
+        bail!("FetchChunked can't be sync");
+    }
+    query.max_result_bytes = conn.connect_options.max_result_bytes;
+
+    l.push_value(4);
+    let on_chunk = l.reference();
+
+    run_async(async move {
+        // the next chunk is only fetched once lua is done with this one
+        let chunk_traceback = traceback.clone();
+        let deliver = move |query: Query, rows: Vec<MySqlRow>| {
+            let (tx, rx) = oneshot::channel();
+            wait_lua_tick(chunk_traceback.clone(), move |l| {
+                let res = process_rows(l, &rows, &query).map(|_| {
+                    l.pcall_ignore_function_ref(on_chunk, 1, 0);
+                });
+                let _ = tx.send((query, res));
+            });
+            async move { rx.await.ok() }
+        };
+
+        // nothing to report if the server is shutting down
+        if let Some((mut query, res)) = fetch_chunks(conn, query, chunk_size, deliver).await {
+            wait_lua_tick(traceback.clone(), move |l| {
+                l.dereference(on_chunk);
+                query.process_result(l, res.map(QueryResult::Count), Some(&traceback));
+            });
+        }
+    });
+
+    Ok(0)
+}
+
+// every chunk is a query of its own, nothing is held on the connection in between so a
+// reconnect between chunks doesn't break it
+// deliver hands a chunk over and gives the query back once it's done with it, None if it never will
+async fn fetch_chunks<F, Fut>(
+    conn: Arc<Conn>,
+    mut query: Query,
+    chunk_size: u64,
+    mut deliver: F,
+) -> Option<(Query, Result<u64>)>
+where
+    F: FnMut(Query, Vec<MySqlRow>) -> Fut,
+    Fut: Future<Output = Option<(Query, Result<()>)>>,
+{
+    let base_query = std::mem::take(&mut query.query);
+    let base_params = std::mem::take(&mut query.params);
+    let mut total = 0;
+
+    loop {
+        // raw queries can't bind, the numbers are validated so they can go in as is
+        if query.raw {
+            query.query = format!("{} LIMIT {} OFFSET {}", base_query, chunk_size, total);
+        } else {
+            query.query = format!("{} LIMIT ? OFFSET ?", base_query);
+            query.params = base_params.clone();
+            query.params.push(Param::BigUnsigned(chunk_size));
+            query.params.push(Param::BigUnsigned(total));
+        }
+
+        let rows = match internal_query(conn.clone(), &mut query).await {
+            Ok(QueryResult::Rows(rows)) => rows,
+            Ok(_) => {
+                let err = anyhow!("FetchChunked only works with SELECT queries");
+                return Some((query, Err(err)));
+            }
+            Err(e) => return Some((query, Err(e))),
+        };
+
+        let count = rows.len() as u64;
+        if count == 0 {
+            break;
+        }
+        if count > chunk_size {
+            let err = anyhow!(
+                "chunk returned {} rows but chunk_size is {}, the query can't have a LIMIT of its own",
+                count,
+                chunk_size
+            );
+            return Some((query, Err(err)));
+        }
+        total += count;
+
+        let (returned_query, res) = deliver(query, rows).await?;
+        query = returned_query;
+        if let Err(e) = res {
+            return Some((query, Err(e)));
+        }
+
+        if count < chunk_size {
+            break;
+        }
+    }
+
+    Some((query, Ok(total)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::Executor as _;
+
+    use super::fetch_chunks;
+    use crate::{
+        conn::{Conn, ConnectOptions},
+        query::{Query, QueryType},
+        test_db,
+    };
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn fetches_every_row_once() {
+        test_db::run(async {
+            let mut opts = ConnectOptions::new();
+            opts.inner = test_db::options();
+            let conn = Arc::new(Conn::new(opts, String::new()));
+            conn.start().await.unwrap();
+            {
+                let mut inner = conn.inner.lock().await;
+                let inner = inner.as_mut().unwrap();
+                // temporary tables belong to the session, the chunks run on the same one
+                inner
+                    .execute("CREATE TEMPORARY TABLE goobie_test_chunks (id INT PRIMARY KEY)")
+                    .await
+                    .unwrap();
+                inner
+                    .execute(
+                        "INSERT INTO goobie_test_chunks (id) WITH RECURSIVE seq (n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM seq WHERE n < 25) SELECT n FROM seq",
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let query = Query::new(
+                "SELECT a.id, b.id FROM goobie_test_chunks a JOIN goobie_test_chunks b ON b.id = a.id ORDER BY a.id".to_string(),
+                QueryType::FetchAll,
+            );
+            let mut chunks = Vec::new();
+            let (_, res) = fetch_chunks(conn, query, 10, |query, rows| {
+                chunks.push(rows.len());
+                async move { Some((query, Ok(()))) }
+            })
+            .await
+            .unwrap();
+
+            assert_eq!(chunks, [10, 10, 5]);
+            assert_eq!(res.unwrap(), 25);
+        });
+    }
+}
@@ -0,0 +1,555 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures_util::TryStreamExt as _;
+use gmod::*;
+use serde_json::{json, Value};
+use sqlx::{mysql::MySqlRow, Column as _, Executor as _, Row as _, TypeInfo as _};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    constants::EXPORT_PROGRESS_ROWS,
+    cstr_from_args,
+    error::handle_error,
+    query::{
+        self, escape,
+        param::Param,
+        process::{is_binary_type, process_row, type_name},
+        value::{decode, ColumnValue},
+        Query, QueryType,
+    },
+    run_async,
+    runtime::{ensure_running, shutdown_signal},
+    GLOBAL_TABLE_NAME,
+};
+
+use super::{
+    apply_keyset,
+    export::{parse_total_hint, Progress},
+    reject_raw, Conn, KEYSET_SLOT,
+};
+
+const META_NAME: LuaCStr = cstr_from_args!(GLOBAL_TABLE_NAME, "_cursor");
+
+pub const METHODS: &[LuaReg] = lua_regs![
+    "Next" => next,
+    "Close" => close,
+    "Token" => token,
+
+    "__gc" => __gc,
+];
+
+pub fn setup(l: lua::State) {
+    l.new_metatable(META_NAME);
+    {
+        l.register(std::ptr::null(), METHODS.as_ptr());
+
+        l.push_value(-1); // Pushes the metatable to the top of the stack
+        l.set_field(-2, c"__index");
+    }
+    l.pop();
+}
+
+type RowRequest = oneshot::Sender<Result<Option<MySqlRow>>>;
+
+// keeps a row stream open on the connection and hands out rows one at a time
+// the connection stays locked until the cursor is closed, garbage collected or runs out of rows
+pub struct Cursor {
+    query: Arc<Query>,
+    requests: Mutex<Option<mpsc::UnboundedSender<RowRequest>>>,
+    resume: Option<Resume>,
+    progress: Progress,
+    counter: ProgressCounter,
+}
+
+// rows handed out so far, on_progress gets called every EXPORT_PROGRESS_ROWS rows like FetchToFile
+// and once more with what's left when the cursor runs out
+#[derive(Default)]
+struct ProgressCounter {
+    rows: AtomicU64,
+    reported: AtomicU64,
+}
+
+impl ProgressCounter {
+    // returns the row count to report, if it's time to
+    fn advance(&self, got_row: bool) -> Option<u64> {
+        let rows = if got_row {
+            self.rows.fetch_add(1, Ordering::AcqRel) + 1
+        } else {
+            self.rows.load(Ordering::Acquire)
+        };
+        let due = if got_row {
+            rows % EXPORT_PROGRESS_ROWS == 0
+        } else {
+            rows != self.reported.load(Ordering::Acquire)
+        };
+        if !due {
+            return None;
+        }
+        self.reported.store(rows, Ordering::Release);
+        Some(rows)
+    }
+}
+
+// tracks the key of the last row handed out, so a new cursor can pick up after it
+struct Resume {
+    key_column: String,
+    // ties a token to the query it came from
+    query_hash: u64,
+    last_key: Mutex<Option<Value>>,
+}
+
+impl Resume {
+    fn track(&self, row: &MySqlRow) -> Result<()> {
+        let idx = match row
+            .columns()
+            .iter()
+            .position(|c| c.name() == escape::unqualified(&self.key_column))
+        {
+            Some(idx) => idx,
+            None => bail!("column '{}' is not in the result", self.key_column),
+        };
+        let column_type = type_name(row.column(idx).type_info().name());
+        let key = match decode(row, idx, column_type)? {
+            ColumnValue::Int(n) => json!(n),
+            ColumnValue::UInt(n) => json!(n),
+            ColumnValue::Text(s) => json!(s),
+            // binary keys (or text that isn't utf8) would get mangled as a json string
+            ColumnValue::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) if !is_binary_type(column_type) => json!(s),
+                _ => json!({ "b": STANDARD.encode(bytes) }),
+            },
+            _ => bail!("resume key must be an integer or a string"),
+        };
+        *self.last_key.lock().unwrap() = Some(key);
+        Ok(())
+    }
+
+    fn token(&self) -> Option<String> {
+        let last_key = self.last_key.lock().unwrap();
+        let token = json!({ "q": format!("{:016x}", self.query_hash), "k": last_key.as_ref()? });
+        Some(STANDARD.encode(token.to_string()))
+    }
+
+    // returns the key to continue after
+    fn read_token(&self, token: &[u8]) -> Result<Value> {
+        let decoded = STANDARD
+            .decode(token)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok());
+        let (hash, key) = match decoded {
+            Some(Value::Object(mut token)) => (token.remove("q"), token.remove("k")),
+            _ => bail!("invalid resume token"),
+        };
+        match (hash, key) {
+            (Some(Value::String(hash)), Some(key))
+                if hash == format!("{:016x}", self.query_hash) =>
+            {
+                Ok(key)
+            }
+            (Some(_), Some(_)) => bail!("resume token belongs to a different query"),
+            _ => bail!("invalid resume token"),
+        }
+    }
+}
+
+// fnv-1a, the token has to stay valid across restarts so the std hasher can't be used
+fn hash_query(query: &str, key_column: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in query.bytes().chain([0]).chain(key_column.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn key_to_param(key: Value) -> Result<Param> {
+    Ok(match key {
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(n), _) => Param::BigInt(n),
+            (_, Some(n)) => Param::BigUnsigned(n),
+            _ => bail!("resume key must be an integer or a string"),
+        },
+        Value::String(s) => Param::String(s.into_bytes()),
+        Value::Object(key) => match key.get("b") {
+            Some(Value::String(b)) => match STANDARD.decode(b) {
+                Ok(bytes) => Param::String(bytes),
+                Err(_) => bail!("invalid resume token"),
+            },
+            _ => bail!("invalid resume token"),
+        },
+        _ => bail!("resume key must be an integer or a string"),
+    })
+}
+
+impl Cursor {
+    fn open(conn: Arc<Conn>, query: Query, resume: Option<Resume>, progress: Progress) -> Self {
+        let query = Arc::new(query);
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        conn.open_cursors.fetch_add(1, Ordering::AcqRel);
+        let task_query = query.clone();
+        run_async(async move {
+            let cancelled = tokio::select! {
+                _ = stream_rows(conn.clone(), task_query, rx) => false,
+                _ = shutdown_signal() => true,
+            };
+            // the stream was dropped in the middle of the result, the connection can't be used anymore
+            if cancelled {
+                conn.inner.lock().await.take();
+                conn.connection_id.store(0, Ordering::Release);
+            }
+            conn.open_cursors.fetch_sub(1, Ordering::AcqRel);
+        });
+
+        Cursor {
+            query,
+            requests: Mutex::new(Some(tx)),
+            resume,
+            progress,
+            counter: ProgressCounter::default(),
+        }
+    }
+
+    #[inline]
+    fn new_userdata(self, l: lua::State) {
+        let ud = Arc::new(self);
+        let ud = Arc::into_raw(ud);
+        l.new_userdata(ud, Some(META_NAME));
+    }
+
+    #[inline]
+    fn extract_userdata(l: lua::State) -> Result<Arc<Self>> {
+        let cursor_ptr = l.get_userdata::<*const Self>(1, Some(META_NAME))?;
+        let cursor_ptr = *cursor_ptr;
+
+        unsafe {
+            Arc::increment_strong_count(cursor_ptr);
+        }
+
+        Ok(unsafe { Arc::from_raw(cursor_ptr) })
+    }
+
+    #[inline]
+    fn extract_userdata_consumed(l: lua::State) -> Result<Arc<Self>> {
+        let cursor_ptr = l.get_userdata::<*const Self>(1, Some(META_NAME))?;
+        Ok(unsafe { Arc::from_raw(*cursor_ptr) })
+    }
+
+    // dropping the sender ends the stream task, which releases the connection
+    fn close(&self) {
+        let _ = self.requests.lock().unwrap().take();
+    }
+}
+
+async fn stream_rows(
+    conn: Arc<Conn>,
+    query: Arc<Query>,
+    mut requests: mpsc::UnboundedReceiver<RowRequest>,
+) {
+    // high priority queries that are already waiting go first, the cursor then holds the connection
+    let mut inner_conn_mutex = conn.lock_queue(false).await;
+    let inner_conn = match inner_conn_mutex.as_mut() {
+        Some(conn) => conn,
+        None => {
+            while let Some(reply) = requests.recv().await {
+                let _ = reply.send(Err(anyhow::anyhow!("connection is not established")));
+            }
+            return;
+        }
+    };
+
+    let mut stream = if query.raw {
+        inner_conn.fetch(query.query.as_str())
+    } else {
+        inner_conn.fetch(query::build_query(
+            query.query.as_str(),
+            query.params.clone(),
+        ))
+    };
+
+    while let Some(reply) = requests.recv().await {
+        let res = stream.try_next().await.map_err(Into::into);
+        let done = !matches!(res, Ok(Some(_)));
+        let _ = reply.send(res);
+        if done {
+            break;
+        }
+    }
+}
+
+pub(super) fn new(l: lua::State, conn: Arc<Conn>) -> Result<i32> {
+    let query_str = l.check_string(2)?.to_string();
+    conn.check_query_length(&query_str)?;
+
+    // checked before parsing so the default value reference isn't leaked
+    let mut resume = None;
+    let mut after = None;
+    if l.lua_type(3) == LUA_TTABLE && l.get_field_type_or_nil(3, c"resume_key", LUA_TSTRING)? {
+        let key_column = l.get_string_unchecked(-1).into_owned();
+        l.pop();
+        reject_raw(l, 3, "resume_key")?;
+        let query_str = query_str.trim_end().trim_end_matches(';');
+        if query_str.matches(KEYSET_SLOT).count() != 1 {
+            bail!(
+                "query must contain {} exactly once to use resume_key",
+                KEYSET_SLOT
+            );
+        }
+        let state = Resume {
+            query_hash: hash_query(query_str, &key_column),
+            key_column,
+            last_key: Mutex::new(None),
+        };
+        if l.get_field_type_or_nil(3, c"resume", LUA_TSTRING)? {
+            let key = state.read_token(l.get_binary_string(-1).unwrap_or_default());
+            l.pop();
+            let key = key?;
+            after = Some(key_to_param(key.clone())?);
+            *state.last_key.lock().unwrap() = Some(key);
+        }
+        resume = Some(state);
+    }
+
+    let total_hint = if l.lua_type(3) == LUA_TTABLE {
+        parse_total_hint(l, 3)?
+    } else {
+        None
+    };
+
+    let mut query = Query::new(query_str, QueryType::FetchOne);
+    query.parse_options(l, 3, false)?;
+
+    let mut on_progress = LUA_NOREF;
+    if l.lua_type(3) == LUA_TTABLE && l.get_field_type_or_nil(3, c"on_progress", LUA_TFUNCTION)? {
+        on_progress = l.reference();
+    }
+    let progress = Progress {
+        callback: on_progress,
+        total_hint,
+    };
+
+    if let Some(resume) = &resume {
+        let trimmed_len = query.query.trim_end().trim_end_matches(';').len();
+        query.query.truncate(trimmed_len);
+        apply_keyset(&mut query, &resume.key_column, after);
+    }
+
+    Cursor::open(conn, query, resume, progress).new_userdata(l);
+
+    Ok(1)
+}
+
+#[lua_function]
+fn next(l: lua::State) -> Result<i32> {
+    ensure_running()?;
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let cursor = Cursor::extract_userdata(l)?;
+    l.check_function(2)?;
+    l.push_value(2);
+    let callback = l.reference();
+
+    let (tx, rx) = oneshot::channel();
+    let sent = match cursor.requests.lock().unwrap().as_ref() {
+        Some(requests) => requests.send(tx).is_ok(),
+        None => false,
+    };
+
+    run_async(async move {
+        // a closed or finished cursor just keeps returning no rows
+        let res = if sent {
+            rx.await.unwrap_or(Ok(None))
+        } else {
+            Ok(None)
+        };
+
+        wait_lua_tick(traceback.clone(), move |l| {
+            // an error ends the cursor too, so both report what it got up to
+            let got_row = matches!(res, Ok(Some(_)));
+            let res = res.and_then(|row| {
+                if let (Some(resume), Some(row)) = (&cursor.resume, &row) {
+                    resume.track(row)?;
+                }
+                process_row(l, row, &cursor.query)
+            });
+            let (returns_count, err_msg) = match res {
+                Ok(n) => {
+                    l.push_nil();
+                    l.insert(-n - 1);
+                    (n + 1, None)
+                }
+                // handle_error pushes the error as a table to the stack
+                Err(e) => (1, Some(handle_error(l, e))),
+            };
+
+            let (called_function, _) = l.pcall_ignore_function_ref(callback, returns_count, 0);
+            if !called_function {
+                if let Some(err_msg) = err_msg {
+                    l.error_no_halt(&err_msg, Some(&traceback));
+                }
+            }
+
+            l.dereference(callback);
+
+            if let Some(rows) = cursor.counter.advance(got_row) {
+                cursor.progress.call(l, rows);
+            }
+        });
+    });
+
+    Ok(0)
+}
+
+// nil if the cursor wasn't opened with resume_key or nothing was read yet
+#[lua_function]
+fn token(l: lua::State) -> Result<i32> {
+    let cursor = Cursor::extract_userdata(l)?;
+    match cursor.resume.as_ref().and_then(Resume::token) {
+        Some(token) => l.push_string(&token),
+        None => l.push_nil(),
+    }
+    Ok(1)
+}
+
+#[lua_function]
+fn close(l: lua::State) -> Result<i32> {
+    let cursor = Cursor::extract_userdata(l)?;
+    cursor.close();
+    Ok(0)
+}
+
+#[lua_function]
+fn __gc(l: lua::State) -> Result<i32> {
+    let cursor = match Cursor::extract_userdata_consumed(l) {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(0),
+    };
+
+    cursor.close();
+    l.dereference(cursor.progress.callback);
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::Row as _;
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::{hash_query, stream_rows, ProgressCounter};
+    use crate::{
+        conn::{export::fraction, Conn, ConnectOptions},
+        query::{Query, QueryType},
+        test_db,
+    };
+
+    #[test]
+    fn query_hash_is_stable() {
+        // fnv-1a of a single 0 byte, tokens from older builds have to keep working
+        assert_eq!(hash_query("", ""), 0xaf63bd4c8601b7df);
+        assert_eq!(hash_query("SELECT 1", "id"), hash_query("SELECT 1", "id"));
+    }
+
+    #[test]
+    fn key_column_is_part_of_the_hash() {
+        assert_ne!(hash_query("SELECT 1", "id"), hash_query("SELECT 1", "uid"));
+        // the separator keeps the query and the column from running into each other
+        assert_ne!(hash_query("ab", "c"), hash_query("a", "bc"));
+    }
+
+    #[test]
+    fn progress_is_reported_in_batches_and_at_the_end() {
+        let counter = ProgressCounter::default();
+        let mut reports = (0..2500)
+            .filter_map(|_| counter.advance(true))
+            .collect::<Vec<_>>();
+        reports.extend(counter.advance(false));
+        assert_eq!(reports, [1000, 2000, 2500]);
+
+        // Next keeps returning no rows once it's done, that's not reported again
+        assert_eq!(counter.advance(false), None);
+
+        let counter = ProgressCounter::default();
+        assert_eq!(counter.advance(false), None);
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn progress_reaches_the_hint() {
+        test_db::run(async {
+            let mut opts = ConnectOptions::new();
+            opts.inner = test_db::options();
+            let conn = Arc::new(Conn::new(opts, String::new()));
+            conn.start().await.unwrap();
+
+            let query = Query::new(
+                "WITH RECURSIVE seq (n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM seq WHERE n < 1000) SELECT n FROM seq"
+                    .to_string(),
+                QueryType::FetchAll,
+            );
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(stream_rows(conn.clone(), Arc::new(query), rx));
+
+            // what Next does with each row it hands out
+            let counter = ProgressCounter::default();
+            let mut progress = Vec::new();
+            loop {
+                let (reply, row) = oneshot::channel();
+                tx.send(reply).unwrap();
+                let row = row.await.unwrap().unwrap();
+                if let Some(rows) = counter.advance(row.is_some()) {
+                    progress.push(fraction(rows, 1000));
+                }
+                if row.is_none() {
+                    break;
+                }
+            }
+
+            assert_eq!(progress.len(), 1);
+            assert!((progress[0] - 1.0).abs() < f64::EPSILON);
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn pulls_rows_on_demand() {
+        test_db::run(async {
+            let mut opts = ConnectOptions::new();
+            opts.inner = test_db::options();
+            let conn = Arc::new(Conn::new(opts, String::new()));
+            conn.start().await.unwrap();
+
+            let query = Query::new(
+                "WITH RECURSIVE seq (n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM seq WHERE n < 100) SELECT n FROM seq"
+                    .to_string(),
+                QueryType::FetchAll,
+            );
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(stream_rows(conn.clone(), Arc::new(query), rx));
+
+            let mut next = 1;
+            for _ in 0..10 {
+                for _ in 0..10 {
+                    let (reply, row) = oneshot::channel();
+                    tx.send(reply).unwrap();
+                    let row = row.await.unwrap().unwrap().unwrap();
+                    assert_eq!(row.get::<i64, _>(0), next);
+                    next += 1;
+                }
+            }
+
+            let (reply, row) = oneshot::channel();
+            tx.send(reply).unwrap();
+            assert!(row.await.unwrap().unwrap().is_none());
+
+            // the stream is done, the connection is free again
+            assert!(conn.inner.lock().await.is_some());
+        });
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use futures_util::TryStreamExt as _;
+use gmod::*;
+use serde_json::Value;
+use sqlx::{mysql::MySqlRow, Column as _, Executor as _, Row as _};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt as _, BufWriter},
+};
+
+use crate::{
+    constants::EXPORT_PROGRESS_ROWS,
+    query::{
+        self,
+        value::{column_value, row_object},
+        Query, QueryResult, QueryType,
+    },
+    run_async,
+};
+
+use super::Conn;
+
+// files can only be written inside the data folder, same as file.Write
+const DATA_DIR: &str = "garrysmod/data";
+
+enum Format {
+    Csv { delimiter: u8, header: bool },
+    Ndjson,
+}
+
+pub(super) fn new(l: lua::State, conn: Arc<Conn>) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+
+    let query_str = l.check_string(2)?.into_owned();
+    conn.check_query_length(&query_str)?;
+    let path = contained_path(DATA_DIR, &l.check_string(3)?)?;
+    let mut format = match l.check_string(4)?.as_ref() {
+        "csv" => Format::Csv {
+            delimiter: b',',
+            header: true,
+        },
+        "ndjson" => Format::Ndjson,
+        name => bail!("unknown format '{}', expected csv or ndjson", name),
+    };
+
+    // validated before anything gets referenced so nothing leaks on a bad option
+    let has_options = l.lua_type(5) == LUA_TTABLE;
+    if let (Format::Csv { delimiter, header }, true) = (&mut format, has_options) {
+        if l.get_field_type_or_nil(5, c"delimiter", LUA_TSTRING)? {
+            let s = l.get_string_unchecked(-1).into_owned();
+            l.pop();
+            if s.len() != 1 {
+                bail!("delimiter must be a single character");
+            }
+            *delimiter = s.as_bytes()[0];
+        }
+
+        if l.get_field_type_or_nil(5, c"header", LUA_TBOOLEAN)? {
+            *header = l.get_boolean(-1);
+            l.pop();
+        }
+    }
+
+    let total_hint = if has_options {
+        parse_total_hint(l, 5)?
+    } else {
+        None
+    };
+
+    let mut query = Query::new(query_str, QueryType::FetchAll);
+    query.parse_callback_or_options(l, 5)?;
+    if query.sync {
+        bail!("FetchToFile can't be sync");
+    }
+
+    let mut on_progress = LUA_NOREF;
+    if has_options && l.get_field_type_or_nil(5, c"on_progress", LUA_TFUNCTION)? {
+        on_progress = l.reference();
+    }
+
+    run_async(async move {
+        let progress = Progress {
+            callback: on_progress,
+            total_hint,
+        };
+        let res = export(conn, &query, path, format, progress, &traceback).await;
+        wait_lua_tick(traceback.clone(), move |l| {
+            l.dereference(on_progress);
+            query.process_result(l, res.map(QueryResult::Count), Some(&traceback));
+        });
+    });
+
+    Ok(0)
+}
+
+// without a COUNT(*) the total isn't known, a hint lets on_progress report how far along it is
+pub(super) fn parse_total_hint(l: lua::State, arg_n: i32) -> Result<Option<u64>> {
+    if !l.get_field_type_or_nil(arg_n, c"total_hint", LUA_TNUMBER)? {
+        return Ok(None);
+    }
+    let hint = l.to_number(-1);
+    l.pop();
+    if hint < 1.0 || hint.fract() != 0.0 {
+        bail!("total_hint must be a positive integer");
+    }
+    Ok(Some(hint as u64))
+}
+
+// capped at 1 in case the hint was off
+pub(super) fn fraction(rows: u64, total_hint: u64) -> f64 {
+    (rows as f64 / total_hint as f64).min(1.0)
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct Progress {
+    pub callback: i32,
+    pub total_hint: Option<u64>,
+}
+
+impl Progress {
+    // (rows, fraction), the fraction is only passed with a hint
+    pub fn call(&self, l: lua::State, rows: u64) {
+        if self.callback == LUA_NOREF {
+            return;
+        }
+        l.push_number(rows);
+        match self.total_hint {
+            Some(total) => {
+                l.push_number(fraction(rows, total));
+                l.pcall_ignore_function_ref(self.callback, 2, 0);
+            }
+            None => {
+                l.pcall_ignore_function_ref(self.callback, 1, 0);
+            }
+        }
+    }
+
+    fn report(&self, rows: u64, traceback: &str) {
+        if self.callback == LUA_NOREF {
+            return;
+        }
+        let progress = *self;
+        wait_lua_tick(traceback.to_owned(), move |l| progress.call(l, rows));
+    }
+}
+
+// only relative paths that stay inside `root`
+pub(super) fn contained_path(root: &str, path: &str) -> Result<PathBuf> {
+    let rel = Path::new(path);
+    if path.is_empty()
+        || !rel
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        bail!("path must be relative to {} and can't contain '..'", root);
+    }
+    Ok(Path::new(root).join(rel))
+}
+
+async fn export(
+    conn: Arc<Conn>,
+    query: &Query,
+    path: PathBuf,
+    format: Format,
+    progress: Progress,
+    traceback: &str,
+) -> Result<u64> {
+    // tokio::fs only borrows a blocking thread for each buffered write, the runtime has just one of
+    // them so holding it for the whole export would stall everything else that needs it
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = BufWriter::new(File::create(&path).await?);
+
+    let rows = stream_rows(conn, query, &format, &mut file, &progress, traceback).await?;
+    file.flush().await?;
+    Ok(rows)
+}
+
+async fn stream_rows(
+    conn: Arc<Conn>,
+    query: &Query,
+    format: &Format,
+    file: &mut BufWriter<File>,
+    progress: &Progress,
+    traceback: &str,
+) -> Result<u64> {
+    let mut inner_conn_mutex = conn.inner.lock().await;
+    let inner_conn = match inner_conn_mutex.as_mut() {
+        Some(conn) => conn,
+        None => bail!("connection is not established"),
+    };
+
+    let mut stream = if query.raw {
+        inner_conn.fetch(query.query.as_str())
+    } else {
+        inner_conn.fetch(query::build_query(
+            query.query.as_str(),
+            query.params.clone(),
+        ))
+    };
+
+    let mut rows = 0;
+    while let Some(row) = stream.try_next().await? {
+        let mut line = Vec::new();
+        match format {
+            Format::Csv { delimiter, header } => {
+                // column names are only known once the first row is in
+                if rows == 0 && *header {
+                    write_csv_header(&mut line, &row, *delimiter);
+                }
+                write_csv_row(&mut line, &row, *delimiter)?;
+            }
+            Format::Ndjson => write_ndjson_row(&mut line, &row)?,
+        }
+
+        file.write_all(&line).await?;
+
+        rows += 1;
+        if rows % EXPORT_PROGRESS_ROWS == 0 {
+            progress.report(rows, traceback);
+        }
+    }
+
+    // the last rows that didn't make a full batch, so progress always ends at the final count
+    if rows % EXPORT_PROGRESS_ROWS != 0 {
+        progress.report(rows, traceback);
+    }
+
+    Ok(rows)
+}
+
+fn write_csv_header(line: &mut Vec<u8>, row: &MySqlRow, delimiter: u8) {
+    for (idx, column) in row.columns().iter().enumerate() {
+        if idx > 0 {
+            line.push(delimiter);
+        }
+        write_csv_field(line, column.name(), delimiter);
+    }
+    line.push(b'\n');
+}
+
+fn write_csv_row(line: &mut Vec<u8>, row: &MySqlRow, delimiter: u8) -> Result<()> {
+    for idx in 0..row.len() {
+        if idx > 0 {
+            line.push(delimiter);
+        }
+        match column_value(row, idx)? {
+            // NULL is an empty field
+            Value::Null => {}
+            Value::String(s) => write_csv_field(line, &s, delimiter),
+            value => line.extend_from_slice(value.to_string().as_bytes()),
+        }
+    }
+    line.push(b'\n');
+    Ok(())
+}
+
+// quoted only when needed, quotes inside are doubled
+fn write_csv_field(line: &mut Vec<u8>, s: &str, delimiter: u8) {
+    if !s
+        .bytes()
+        .any(|b| b == delimiter || b == b'"' || b == b'\n' || b == b'\r')
+    {
+        line.extend_from_slice(s.as_bytes());
+        return;
+    }
+
+    line.push(b'"');
+    line.extend_from_slice(s.replace('"', "\"\"").as_bytes());
+    line.push(b'"');
+}
+
+fn write_ndjson_row(line: &mut Vec<u8>, row: &MySqlRow) -> Result<()> {
+    serde_json::to_writer(&mut *line, &row_object(row)?)?;
+    line.push(b'\n');
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{contained_path, write_csv_field};
+
+    #[test]
+    fn paths_stay_inside_the_root() {
+        assert_eq!(
+            contained_path("garrysmod/data", "exports/a.csv").unwrap(),
+            Path::new("garrysmod/data/exports/a.csv")
+        );
+        for path in [
+            "",
+            "../a.csv",
+            "exports/../../a.csv",
+            "/etc/passwd",
+            "./a.csv",
+        ] {
+            assert!(contained_path("garrysmod/data", path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        let mut line = Vec::new();
+        write_csv_field(&mut line, "plain", b',');
+        write_csv_field(&mut line, "a,b", b',');
+        write_csv_field(&mut line, "say \"hi\"", b';');
+        assert_eq!(line, b"plain\"a,b\"\"say \"\"hi\"\"\"");
+    }
+}
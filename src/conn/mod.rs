@@ -1,25 +1,40 @@
 use std::{
     self,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicI32, Ordering},
+        atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Result};
 use gmod::{lua::*, *};
-use sqlx::{mysql::MySqlConnection, Connection};
-use tokio::sync::Mutex;
+use sqlx::{mysql::MySqlConnection, Connection, Executor as _, Row as _};
+use tokio::sync::{Mutex, MutexGuard, Notify};
 
+mod chunked;
+mod cursor;
+mod export;
 pub mod on_gmod_open;
 mod options;
+mod registry;
 mod state;
 mod transaction;
+mod upsert;
 
 use options::Options as ConnectOptions;
 use state::{AtomicState, State};
 
-use crate::{cstr_from_args, error::handle_error, query, run_async, wait_async, GLOBAL_TABLE_NAME};
+use crate::{
+    cstr_from_args,
+    error::{
+        handle_error, is_cancelled, is_connection_lost, mysql_error_number, DeadlockInfo, KindError,
+    },
+    query, run_async,
+    runtime::{ensure_running, shutdown_signal},
+    wait_async, GLOBAL_TABLE_NAME, HEALTHCHECK_TIMEOUT, MAX_CONNECT_RETRY_DELAY,
+};
 
 const META_NAME: LuaCStr = cstr_from_args!(GLOBAL_TABLE_NAME, "_connection");
 
@@ -32,14 +47,38 @@ pub const METHODS: &[LuaReg] = lua_regs![
     "DisconnectSync" => start_disconnect_sync,
 
     "State" => get_state,
+    "OnStateChange" => on_state_change,
     "Ping" => ping,
+    "Healthcheck" => healthcheck,
+    "KillCurrentQuery" => kill_current_query,
+    "TLSInfo" => tls_info,
 
     "Execute" => execute,
+    "ExecuteMany" => execute_many,
+    "ExecuteFile" => execute_file,
+    "Upsert" => upsert,
     "FetchOne" => fetch_one,
     "Fetch" => fetch,
+    "FetchValue" => fetch_value,
+    "FetchRow" => fetch_row,
+    "FetchPipeline" => fetch_pipeline,
+    "FetchPage" => fetch_page,
+    "FetchKeyset" => fetch_keyset,
+    "FetchByKey" => fetch_by_key,
+    "FetchChunked" => fetch_chunked,
+    "OpenCursor" => open_cursor,
+    "FetchToFile" => fetch_to_file,
+
+    "UseDatabase" => use_database,
+    "Escape" => escape,
+    "Prefixed" => prefixed,
+    "ServerStatus" => server_status,
+    "NextAutoIncrement" => next_auto_increment,
 
     "Begin" => transaction::new,
     "BeginSync" => transaction::new_sync,
+    "BeginReadOnly" => transaction::new_read_only,
+    "BeginReadOnlySync" => transaction::new_read_only_sync,
 
     "IsConnected" => is_connected,
     "IsConnecting" => is_connecting,
@@ -52,6 +91,8 @@ pub const METHODS: &[LuaReg] = lua_regs![
 
 #[repr(C)]
 pub struct Conn {
+    // what goobie_mysql.Connections() lists it as
+    pub id: i32,
     pub inner: Arc<Mutex<Option<MySqlConnection>>>,
     pub connect_options: ConnectOptions,
     pub state: AtomicState,
@@ -59,28 +100,148 @@ pub struct Conn {
 
     // this is to avoid deadlock when someone mistakenly tries to run a sync conn:query while in a transaction
     pub transaction_coroutine_ref: AtomicI32, // if any transaction is running
+
+    // shared with queued callbacks, so they can tell if the reference was replaced or released meanwhile
+    pub on_state_change: Arc<AtomicI32>,
+    // taken from the options, same as on_state_change
+    pub on_connect: Arc<AtomicI32>,
+
+    // server side id of the current connection, 0 when not connected
+    pub connection_id: AtomicU64,
+
+    // what the session reported after connecting, None when not connected
+    pub tls_info: std::sync::Mutex<Option<TlsInfo>>,
+
+    // what conn:Escape has to follow, read after connecting and after queries that can change it
+    pub escape_session: std::sync::Mutex<EscapeSession>,
+
+    // what ping_checks_database compares against, the configured one until UseDatabase switches it
+    database: std::sync::Mutex<Option<String>>,
+
+    // high priority queries waiting for the connection, normal ones step aside until it's back to 0
+    high_priority_waiting: AtomicUsize,
+    high_priority_done: Notify,
+
+    // cursors keep the connection locked until they are closed, a sync call would wait for it forever
+    open_cursors: AtomicUsize,
+}
+
+#[derive(Debug, Clone)]
+pub struct EscapeSession {
+    pub charset: String,
+    pub no_backslash_escapes: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    // both empty when the connection is not encrypted
+    pub cipher: String,
+    pub version: String,
 }
 
 impl Conn {
     pub fn new(opts: ConnectOptions, traceback: String) -> Self {
+        let charset = opts.inner.get_charset().to_string();
+        let on_connect = opts.on_connect;
+        let database = opts.inner.get_database().map(str::to_string);
         Conn {
+            id: registry::next_id(),
             inner: Arc::default(),
             connect_options: opts,
             state: AtomicState::new(State::NotConnected),
             traceback,
             transaction_coroutine_ref: AtomicI32::new(LUA_NOREF),
+            on_state_change: Arc::new(AtomicI32::new(LUA_NOREF)),
+            on_connect: Arc::new(AtomicI32::new(on_connect)),
+            connection_id: AtomicU64::new(0),
+            escape_session: std::sync::Mutex::new(EscapeSession {
+                charset,
+                no_backslash_escapes: false,
+            }),
+            tls_info: std::sync::Mutex::new(None),
+            database: std::sync::Mutex::new(database),
+            high_priority_waiting: AtomicUsize::new(0),
+            high_priority_done: Notify::new(),
+            open_cursors: AtomicUsize::new(0),
+        }
+    }
+
+    // the configured database until UseDatabase switches it
+    fn database(&self) -> Option<String> {
+        self.database.lock().unwrap().clone()
+    }
+
+    // the cursor is only closed from lua, which a sync call blocks, so fail instead of hanging
+    pub fn check_no_open_cursor(&self) -> Result<()> {
+        if self.open_cursors.load(Ordering::Acquire) > 0 {
+            return Err(KindError::new(
+                "cursor_open",
+                "can't run a sync call while a cursor is open on this connection, close the cursor first",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // checked before the options are parsed, so a rejected query doesn't leak its callback
+    pub fn check_query_length(&self, query: &str) -> Result<()> {
+        match self.connect_options.max_query_length {
+            Some(max_length) if query.len() > max_length => Err(KindError::new(
+                "query_too_long",
+                format!(
+                    "query is {} bytes long, max_query_length is {}",
+                    query.len(),
+                    max_length
+                ),
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    // the mutex hands the connection out in order, a normal query that gets it while a high priority
+    // one is waiting gives it back and waits for the high priority ones to be done
+    async fn lock_queue(&self, high_priority: bool) -> MutexGuard<'_, Option<MySqlConnection>> {
+        if high_priority {
+            self.high_priority_waiting
+                .fetch_add(1, Ordering::AcqRel);
+            let guard = self.inner.lock().await;
+            if self
+                .high_priority_waiting
+                .fetch_sub(1, Ordering::AcqRel)
+                == 1
+            {
+                self.high_priority_done.notify_waiters();
+            }
+            return guard;
+        }
+
+        loop {
+            let guard = self.inner.lock().await;
+            if self.high_priority_waiting.load(Ordering::Acquire) == 0 {
+                return guard;
+            }
+            // registered before the guard is dropped so the wake up can't be missed
+            let done = self.high_priority_done.notified();
+            drop(guard);
+            if self.high_priority_waiting.load(Ordering::Acquire) != 0 {
+                done.await;
+            }
         }
     }
 
     #[inline]
-    pub fn new_userdata(self, l: lua::State) {
-        let ud = Arc::new(self);
-        let ud = Arc::into_raw(ud);
+    pub fn new_userdata(self, l: lua::State) -> Arc<Self> {
+        let conn = Arc::new(self);
+        let ud = Arc::into_raw(conn.clone());
         l.new_userdata(ud, Some(META_NAME));
+        conn
     }
 
     #[inline]
     pub fn extract_userdata(l: lua::State) -> Result<Arc<Self>> {
+        ensure_running()?;
+
         let conn_ptr = l.get_userdata::<*const Self>(1, Some(META_NAME))?;
         let conn_ptr = *conn_ptr;
 
@@ -140,21 +301,243 @@ impl Conn {
         }
 
         self.set_state(State::Connecting);
-
-        let connect_opts = &self.connect_options.inner;
-
-        match MySqlConnection::connect_with(connect_opts).await {
-            Ok(conn) => {
+        self.connection_id.store(0, Ordering::Release);
+        *self.tls_info.lock().unwrap() = None;
+
+        let database = self.database();
+        match Self::connect_with_retries(&self.connect_options, database.as_deref()).await {
+            Ok(mut conn) => {
+                // cached because the connection is busy with the query by the time KillCurrentQuery needs it
+                let (connection_id, tls_info) = match Self::fetch_session_info(&mut conn).await {
+                    Ok(info) => info,
+                    Err(e) => {
+                        let _ = conn.close().await;
+                        self.set_state(State::Error);
+                        return Err(e);
+                    }
+                };
+                self.connection_id
+                    .store(connection_id, Ordering::Release);
+                *self.tls_info.lock().unwrap() = Some(tls_info);
+                self.refresh_escape_session(&mut conn).await;
                 inner_conn_mutex.replace(conn);
             }
             Err(e) => {
                 self.set_state(State::Error);
-                return Err(e.into());
+                return Err(e);
             }
         };
 
         self.set_state(State::Connected);
 
+        // unlike on_connected this runs for every connection made, no matter how Start was called
+        let on_connect = self.on_connect.load(Ordering::Acquire);
+        if on_connect != LUA_NOREF {
+            let current = self.on_connect.clone();
+            wait_lua_tick(self.traceback.clone(), move |l| {
+                // __gc could have dereferenced it before this tick
+                if current.load(Ordering::Acquire) != on_connect {
+                    return;
+                }
+                l.pcall_ignore_function_ref(on_connect, 0, 0);
+            });
+        }
+
+        Ok(())
+    }
+
+    // only errors from reaching the server are retried, e.g. the server booted before mysql did
+    async fn connect_with_retries(
+        opts: &ConnectOptions,
+        database: Option<&str>,
+    ) -> Result<MySqlConnection> {
+        let mut delay = opts.connect_retry_delay;
+        let mut attempts = 0;
+        loop {
+            match Self::connect(opts, database).await {
+                Err(e) if attempts < opts.connect_retries && is_connection_lost(&e) => {
+                    attempts += 1;
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {},
+                        _ = shutdown_signal() => return Err(e),
+                    }
+                    delay = delay
+                        .saturating_mul(2)
+                        .min(MAX_CONNECT_RETRY_DELAY);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // connects and sets up the session, runs again on every reconnect
+    // database overrides the configured one, it's where UseDatabase left the connection
+    async fn connect(opts: &ConnectOptions, database: Option<&str>) -> Result<MySqlConnection> {
+        let mut conn = match database {
+            Some(database) => {
+                MySqlConnection::connect_with(&opts.inner.clone().database(database)).await?
+            }
+            None => MySqlConnection::connect_with(&opts.inner).await?,
+        };
+
+        let res = match opts.init_timeout {
+            Some(init_timeout) => {
+                match tokio::time::timeout(init_timeout, Self::init(&mut conn, opts)).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        // the connection is stuck in the middle of a query, no point in a graceful close
+                        let _ = conn.close_hard().await;
+                        return Err(KindError::new(
+                            "init_timeout",
+                            format!("connection setup didn't finish within {:?}", init_timeout),
+                        )
+                        .into());
+                    }
+                }
+            }
+            None => Self::init(&mut conn, opts).await,
+        };
+
+        if let Err(e) = res {
+            let _ = conn.close().await;
+            return Err(e);
+        }
+
+        Ok(conn)
+    }
+
+    async fn fetch_session_info(conn: &mut MySqlConnection) -> Result<(u64, TlsInfo)> {
+        let connection_id = sqlx::query_scalar("SELECT CONNECTION_ID()")
+            .fetch_one(&mut *conn)
+            .await?;
+
+        // asking the server is the only way to know, sqlx silently goes without tls when ssl_mode allows it
+        let mut tls_info = TlsInfo {
+            cipher: String::new(),
+            version: String::new(),
+        };
+        let rows = conn
+            .fetch_all("SHOW SESSION STATUS WHERE Variable_name IN ('Ssl_cipher', 'Ssl_version')")
+            .await?;
+        for row in rows {
+            let name: String = row.try_get_unchecked(0)?;
+            let value: String = row.try_get_unchecked(1)?;
+            match name.as_str() {
+                "Ssl_cipher" => tls_info.cipher = value,
+                "Ssl_version" => tls_info.version = value,
+                _ => {}
+            }
+        }
+
+        Ok((connection_id, tls_info))
+    }
+
+    // a failed read keeps what was known before, escaping with a stale charset beats not escaping at all
+    async fn refresh_escape_session(&self, conn: &mut MySqlConnection) {
+        let res: Result<(String, String), _> =
+            sqlx::query_as("SELECT @@character_set_client, @@sql_mode")
+                .fetch_one(&mut *conn)
+                .await;
+        if let Ok((charset, sql_mode)) = res {
+            *self.escape_session.lock().unwrap() = EscapeSession {
+                charset,
+                no_backslash_escapes: sql_mode
+                    .split(',')
+                    .any(|mode| mode == "NO_BACKSLASH_ESCAPES"),
+            };
+        }
+    }
+
+    // escapes with what the session was last read as, no round trip
+    fn escape_tracked(&self, s: &[u8]) -> Vec<u8> {
+        let session = self.escape_session.lock().unwrap().clone();
+        query::escape::string(s, &session.charset, session.no_backslash_escapes)
+    }
+
+    // asks the connection first, in case the session was changed in a way that wasn't picked up (e.g.
+    // a SET NAMES inside a stored procedure), not connected falls back to what was last read
+    async fn escape(&self, s: &[u8]) -> Vec<u8> {
+        {
+            let mut inner_conn = self.lock_queue(false).await;
+            if let Some(inner_conn) = inner_conn.as_mut() {
+                self.refresh_escape_session(inner_conn).await;
+            }
+        }
+        self.escape_tracked(s)
+    }
+
+    // KILL QUERY has to come from another connection, the current one is stuck waiting on the query
+    async fn kill_current_query(&self) -> Result<()> {
+        let connection_id = self.connection_id.load(Ordering::Acquire);
+        if connection_id == 0 {
+            bail!("connection is not established");
+        }
+
+        let mut kill_conn = Self::connect(&self.connect_options, None).await?;
+        let res = kill_conn
+            .execute(format!("KILL QUERY {}", connection_id).as_str())
+            .await;
+        let _ = kill_conn.close().await;
+        res?;
+
+        Ok(())
+    }
+
+    // read from another connection, this one could be in the middle of something else by now
+    async fn deadlock_info(&self) -> Result<Option<String>> {
+        let mut status_conn = Self::connect(&self.connect_options, None).await?;
+        let res = sqlx::query("SHOW ENGINE INNODB STATUS")
+            .fetch_one(&mut status_conn)
+            .await;
+        let _ = status_conn.close().await;
+
+        let status: String = res?.try_get("Status")?;
+        Ok(latest_deadlock_section(&status).map(str::to_string))
+    }
+
+    async fn init(conn: &mut MySqlConnection, opts: &ConnectOptions) -> Result<()> {
+        if opts.require_utf8mb4 {
+            let charset: String = sqlx::query_scalar("SELECT @@character_set_connection")
+                .fetch_one(&mut *conn)
+                .await?;
+            if charset != "utf8mb4" {
+                return Err(KindError::new(
+                    "charset_mismatch",
+                    format!(
+                        "connection charset is {} instead of utf8mb4, emojis and other 4 byte characters would get mangled",
+                        charset
+                    ),
+                )
+                .into());
+            }
+        }
+
+        if let Some(sql_mode) = &opts.sql_mode {
+            sqlx::query("SET SESSION sql_mode = ?")
+                .bind(sql_mode)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        if let Some(wait_timeout) = opts.wait_timeout {
+            sqlx::query("SET SESSION wait_timeout = ?")
+                .bind(wait_timeout)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        if let Some(interactive_timeout) = opts.interactive_timeout {
+            sqlx::query("SET SESSION interactive_timeout = ?")
+                .bind(interactive_timeout)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        // prepared only, they land in the statement cache so the first real run skips the prepare
+        for sql in &opts.warmup_statements {
+            conn.prepare(sql.as_str()).await?;
+        }
+
         Ok(())
     }
 
@@ -170,6 +553,8 @@ impl Conn {
         // even though conn.close could fail, it will still be disconnected so it's better to
         // mark it before attempting to close
         self.set_state(State::Disconnected);
+        self.connection_id.store(0, Ordering::Release);
+        *self.tls_info.lock().unwrap() = None;
 
         if let Some(conn) = inner_conn.take() {
             conn.close().await?;
@@ -185,7 +570,27 @@ impl Conn {
 
     #[inline]
     fn set_state(&self, state: State) {
-        self.state.store(state, Ordering::Release);
+        let new_state = state as i32;
+        let old_state = self.state.swap(state, Ordering::AcqRel) as i32;
+        if old_state == new_state {
+            return;
+        }
+
+        let on_state_change = self.on_state_change.load(Ordering::Acquire);
+        if on_state_change == LUA_NOREF {
+            return;
+        }
+
+        let current = self.on_state_change.clone();
+        wait_lua_tick(self.traceback.clone(), move |l| {
+            // OnStateChange or __gc could have dereferenced it before this tick
+            if current.load(Ordering::Acquire) != on_state_change {
+                return;
+            }
+            l.push_number(old_state);
+            l.push_number(new_state);
+            l.pcall_ignore_function_ref(on_state_change, 2, 0);
+        });
     }
 
     #[inline]
@@ -198,8 +603,61 @@ impl Conn {
 
         inner_conn.ping().await?;
 
+        // catches a connection that ended up on another database, e.g. a reconnect that went wrong
+        let expected = self.database();
+        if let (true, Some(expected)) = (self.connect_options.ping_checks_database, expected) {
+            let current: Option<String> = sqlx::query_scalar("SELECT DATABASE()")
+                .fetch_one(&mut *inner_conn)
+                .await?;
+            if current.as_deref() != Some(expected.as_str()) {
+                return Err(KindError::new(
+                    "database_mismatch",
+                    format!(
+                        "connection is on database {} instead of {}",
+                        current.as_deref().unwrap_or("(none)"),
+                        expected
+                    ),
+                )
+                .into());
+            }
+        }
+
         Ok(())
     }
+
+    // returns how long the ping and the test query took
+    async fn healthcheck(&self) -> Result<Duration> {
+        if self.state() != State::Connected {
+            bail!("connection is not connected ({})", self.state());
+        }
+
+        let start = Instant::now();
+        let check = async {
+            let mut inner_conn = self.inner.lock().await;
+            let inner_conn = match inner_conn.as_mut() {
+                Some(conn) => conn,
+                None => bail!("connection is not established"),
+            };
+
+            inner_conn.ping().await?;
+            inner_conn.execute("SELECT 1").await?;
+
+            Ok(())
+        };
+
+        match tokio::time::timeout(HEALTHCHECK_TIMEOUT, check).await {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(KindError::new(
+                    "timeout",
+                    format!("healthcheck didn't finish within {:?}", HEALTHCHECK_TIMEOUT),
+                )
+                .into())
+            }
+        };
+
+        Ok(start.elapsed())
+    }
 }
 
 impl std::fmt::Display for Conn {
@@ -221,8 +679,8 @@ fn new(l: lua::State) -> Result<i32> {
     let mut opts = ConnectOptions::new();
     opts.parse(l, true)?;
 
-    let conn = Conn::new(opts, traceback);
-    conn.new_userdata(l);
+    let conn = Conn::new(opts, traceback).new_userdata(l);
+    registry::add(l, &conn);
 
     Ok(1)
 }
@@ -266,6 +724,7 @@ fn start_connect(l: lua::State) -> Result<i32> {
 #[lua_function]
 fn start_connect_sync(l: lua::State) -> Result<i32> {
     let conn = Conn::extract_userdata(l)?;
+    conn.check_no_open_cursor()?;
     wait_async(l, async move { conn.start().await })?;
     Ok(0)
 }
@@ -306,6 +765,7 @@ fn start_disconnect(l: lua::State) -> Result<i32> {
 #[lua_function]
 fn start_disconnect_sync(l: lua::State) -> Result<i32> {
     let conn = Conn::extract_userdata(l)?;
+    conn.check_no_open_cursor()?;
     let res = wait_async(l, async move { conn.disconnect().await });
     if let Err(e) = res {
         handle_error(l, e);
@@ -315,12 +775,83 @@ fn start_disconnect_sync(l: lua::State) -> Result<i32> {
 }
 
 async fn internal_query(conn: Arc<Conn>, query: &mut query::Query) -> Result<query::QueryResult> {
-    let mut inner_conn_mutex = conn.inner.lock().await;
+    match query_with_reconnect(&conn, query).await {
+        Err(e) if query.capture_deadlock_info && mysql_error_number(&e) == Some(1213) => {
+            // the error is still worth reporting without it, e.g. the user lacks the PROCESS privilege
+            match conn.deadlock_info().await {
+                Ok(Some(info)) => Err(e.context(DeadlockInfo(info))),
+                _ => Err(e),
+            }
+        }
+        res => res,
+    }
+}
+
+// the query runs once more on the new connection, so it has to be safe to run twice
+async fn query_with_reconnect(conn: &Conn, query: &mut query::Query) -> Result<query::QueryResult> {
+    if !query.retry_after_reconnect || query.background {
+        return query_on_conn(conn, query).await;
+    }
+
+    let connection_id = conn.connection_id.load(Ordering::Acquire);
+    match query_on_conn(conn, query).await {
+        Err(e) if is_connection_lost(&e) => {
+            // another query that lost the same connection could have reconnected already
+            if conn.connection_id.load(Ordering::Acquire) == connection_id {
+                conn.start().await?;
+            }
+            query_on_conn(conn, query).await
+        }
+        res => res,
+    }
+}
+
+async fn query_on_conn(conn: &Conn, query: &mut query::Query) -> Result<query::QueryResult> {
+    query.debug_sql = conn.connect_options.debug_sql;
+
+    // a short lived connection of its own, so a slow query doesn't hold up the queue of the main one
+    if query.background {
+        let database = conn.database();
+        let mut background_conn = Conn::connect(&conn.connect_options, database.as_deref()).await?;
+        let res = query.start(&mut background_conn).await;
+        if !matches!(&res, Err(e) if is_cancelled(e)) {
+            let _ = background_conn.close().await;
+        }
+        return res;
+    }
+
+    let mut inner_conn_mutex = conn.lock_queue(query.high_priority).await;
     let inner_conn = match inner_conn_mutex.as_mut() {
         Some(conn) => conn,
         None => bail!("connection is not established"),
     };
-    query.start(inner_conn).await
+    let res = query.start(inner_conn).await;
+    if let Err(e) = &res {
+        if is_cancelled(e) {
+            // dropping it closes the socket, which makes the server abort the query
+            inner_conn_mutex.take();
+            conn.connection_id.store(0, Ordering::Release);
+            return res;
+        }
+    }
+    let charset_restored = match query.take_charset_restore() {
+        Some(restore) => inner_conn.execute(restore.as_str()).await.is_ok(),
+        None => true,
+    };
+    // every query after this would run with the wrong charset
+    if !charset_restored {
+        inner_conn_mutex.take();
+        conn.connection_id.store(0, Ordering::Release);
+        conn.set_state(State::Error);
+        return res;
+    }
+    if query.changes_escaping() {
+        conn.refresh_escape_session(inner_conn).await;
+    }
+    if let (Ok(_), Some(database)) = (&res, &query.use_database) {
+        *conn.database.lock().unwrap() = Some(database.clone());
+    }
+    res
 }
 
 fn start_query(l: lua::State, query_type: query::QueryType) -> Result<i32> {
@@ -328,10 +859,33 @@ fn start_query(l: lua::State, query_type: query::QueryType) -> Result<i32> {
     let conn = Conn::extract_userdata(l)?;
 
     let query_str = l.check_string(2)?.to_string();
+    conn.check_query_length(&query_str)?;
     let mut query = query::Query::new(query_str, query_type);
-    query.parse_options(l, 3, true)?;
+    // short forms, (sql, callback) and (sql, params, callback)
+    if l.is_function(3) {
+        query.parse_callback_or_options(l, 3)?;
+    } else if l.lua_type(3) == LUA_TTABLE && l.is_function(4) {
+        l.push_value(3);
+        query.bind_params(l)?;
+        l.pop();
+        query.parse_callback_or_options(l, 4)?;
+    } else {
+        query.parse_options(l, 3, true)?;
+    }
+
+    run_query(l, conn, query, traceback)
+}
+
+fn run_query(
+    l: lua::State,
+    conn: Arc<Conn>,
+    mut query: query::Query,
+    traceback: String,
+) -> Result<i32> {
+    query.max_result_bytes = conn.connect_options.max_result_bytes;
 
     if query.sync {
+        conn.check_no_open_cursor()?;
         let (mut query, res) = wait_async(l, async move {
             let res = internal_query(conn, &mut query).await;
             (query, res)
@@ -349,11 +903,86 @@ fn start_query(l: lua::State, query_type: query::QueryType) -> Result<i32> {
     Ok(0)
 }
 
+// the section goes on until the next one, which is TRANSACTIONS
+fn latest_deadlock_section(status: &str) -> Option<&str> {
+    let start = status.find("LATEST DETECTED DEADLOCK\n")?;
+    let section = &status[start..];
+    let end = section
+        .find("\n------------\nTRANSACTIONS")
+        .unwrap_or(section.len());
+    Some(section[..end].trim_end())
+}
+
 #[lua_function]
 fn execute(l: lua::State) -> Result<i32> {
     start_query(l, query::QueryType::Execute)
 }
 
+#[lua_function]
+fn execute_many(l: lua::State) -> Result<i32> {
+    start_query(l, query::QueryType::ExecuteMany)
+}
+
+#[lua_function]
+fn execute_file(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    let path = export::contained_path(GAME_DIR, &l.check_string(2)?)?;
+    let mut query = query::Query::new(String::new(), query::QueryType::Pipeline);
+    query.parse_callback_or_options(l, 3)?;
+    query.max_result_bytes = conn.connect_options.max_result_bytes;
+
+    if query.sync {
+        conn.check_no_open_cursor()?;
+        let (mut query, res) = wait_async(l, async move {
+            let res = execute_file_inner(conn, &mut query, path).await;
+            (query, res)
+        });
+        return Ok(query.process_result(l, res, None));
+    }
+
+    run_async(async move {
+        let res = execute_file_inner(conn, &mut query, path).await;
+        wait_lua_tick(traceback.clone(), move |l| {
+            query.process_result(l, res, Some(&traceback));
+        });
+    });
+
+    Ok(0)
+}
+
+// scripts can be read from anywhere in the game folder, e.g. addons/*/migrations
+const GAME_DIR: &str = "garrysmod";
+
+// statements run one by one as raw queries, so ones that can't be prepared (CREATE PROCEDURE...) work too
+async fn execute_file_inner(
+    conn: Arc<Conn>,
+    query: &mut query::Query,
+    path: PathBuf,
+) -> Result<query::QueryResult> {
+    let script = tokio::fs::read_to_string(path).await?;
+    query.pipeline = script_pipeline(&conn, &script)?;
+    internal_query(conn, query).await
+}
+
+fn script_pipeline(conn: &Conn, script: &str) -> Result<Vec<query::Query>> {
+    let mut pipeline = Vec::new();
+    for statement in query::script::split(script)? {
+        conn.check_query_length(&statement)?;
+        let mut entry = query::Query::new(statement, query::QueryType::Execute);
+        entry.raw = true;
+        pipeline.push(entry);
+    }
+    Ok(pipeline)
+}
+
+#[lua_function]
+fn upsert(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata(l)?;
+    upsert::new(l, conn)
+}
+
 #[lua_function]
 fn fetch_one(l: lua::State) -> Result<i32> {
     start_query(l, query::QueryType::FetchOne)
@@ -364,6 +993,420 @@ fn fetch(l: lua::State) -> Result<i32> {
     start_query(l, query::QueryType::FetchAll)
 }
 
+#[lua_function]
+fn fetch_value(l: lua::State) -> Result<i32> {
+    start_query(l, query::QueryType::FetchValue)
+}
+
+#[lua_function]
+fn fetch_row(l: lua::State) -> Result<i32> {
+    start_query(l, query::QueryType::FetchRow)
+}
+
+#[lua_function]
+fn fetch_pipeline(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    l.check_table(2)?;
+    let mut query = query::Query::new(String::new(), query::QueryType::Pipeline);
+    // each entry is either a query string or { "SELECT ...", params = {...}, ... }
+    for i in 1..=l.len(2) {
+        l.raw_geti(2, i);
+        let mut entry = if l.lua_type(-1) == LUA_TTABLE {
+            l.raw_geti(-1, 1);
+            let query_str = l.check_string(-1)?.into_owned();
+            l.pop();
+            conn.check_query_length(&query_str)?;
+            let mut entry = query::Query::new(query_str, query::QueryType::FetchAll);
+            entry.parse_options(l, l.get_top(), false)?;
+            entry
+        } else {
+            let query_str = l.check_string(-1)?.into_owned();
+            conn.check_query_length(&query_str)?;
+            query::Query::new(query_str, query::QueryType::FetchAll)
+        };
+        l.pop();
+        entry.max_result_bytes = conn.connect_options.max_result_bytes;
+        query.pipeline.push(entry);
+    }
+    query.parse_callback_or_options(l, 3)?;
+
+    run_query(l, conn, query, traceback)
+}
+
+// LIMIT and OFFSET values, lua numbers stop being exact integers past 2^53
+pub(super) fn positive_integer(name: &str, n: f64) -> Result<u64> {
+    if n < 1.0 || n.fract() != 0.0 || n > (1u64 << 53) as f64 {
+        bail!("{} must be a positive integer", name);
+    }
+    Ok(n as u64)
+}
+
+#[lua_function]
+fn fetch_page(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    let query_str = l.check_string(2)?;
+    conn.check_query_length(&query_str)?;
+    let query_str = query_str
+        .trim_end()
+        .trim_end_matches(';')
+        .to_string();
+    let page = positive_integer("page", l.check_number(3)?)?;
+    let per_page = positive_integer("per_page", l.check_number(4)?)?;
+    let offset = match (page - 1).checked_mul(per_page) {
+        Some(offset) => offset,
+        None => bail!("page * per_page is too big"),
+    };
+
+    let mut query = query::Query::new(query_str, query::QueryType::FetchAll);
+    query.parse_callback_or_options(l, 5)?;
+
+    if l.lua_type(5) == LUA_TTABLE && l.get_field_type_or_nil(5, c"with_total", LUA_TBOOLEAN)? {
+        if l.get_boolean(-1) {
+            let total_query = format!("SELECT COUNT(*) FROM ({}) AS goobie_page", query.query);
+            query.total_query = Some((total_query, query.params.clone()));
+        }
+        l.pop();
+    }
+
+    // raw queries can't bind, the numbers are validated above so they can go in as is
+    if query.raw {
+        query.query = format!("{} LIMIT {} OFFSET {}", query.query, per_page, offset);
+    } else {
+        query.query.push_str(" LIMIT ? OFFSET ?");
+        query
+            .params
+            .push(query::param::Param::BigUnsigned(per_page));
+        query
+            .params
+            .push(query::param::Param::BigUnsigned(offset));
+    }
+
+    run_query(l, conn, query, traceback)
+}
+
+// the query marks where the key condition goes with {keyset}, e.g. "SELECT * FROM users WHERE active = 1 AND {keyset}",
+// appending it would break on queries that already have a WHERE, GROUP BY...
+pub(super) const KEYSET_SLOT: &str = "{keyset}";
+
+// filled in after parsing, so the placeholder style the query uses doesn't matter
+pub(super) fn apply_keyset(
+    query: &mut query::Query,
+    key_column: &str,
+    after: Option<query::param::Param>,
+) {
+    let key = query::escape::qualified(key_column);
+    let slot = query.query.find(KEYSET_SLOT).unwrap_or_default();
+    let condition = match after {
+        Some(after) => {
+            // the key goes between the params that come before and after the slot
+            let idx = query::placeholder::count(&query.query[..slot]).min(query.params.len());
+            query.params.insert(idx, after);
+            format!("{} > ?", key)
+        }
+        None => "TRUE".to_string(),
+    };
+    query
+        .query
+        .replace_range(slot..slot + KEYSET_SLOT.len(), &condition);
+    query.query = format!("{} ORDER BY {}", query.query, key);
+}
+
+pub(super) fn reject_raw(l: lua::State, arg_n: i32, what: &str) -> Result<()> {
+    if l.lua_type(arg_n) == LUA_TTABLE && l.get_field_type_or_nil(arg_n, c"raw", LUA_TBOOLEAN)? {
+        let raw = l.get_boolean(-1);
+        l.pop();
+        if raw {
+            bail!("{} can't be used with raw queries", what);
+        }
+    }
+    Ok(())
+}
+
+#[lua_function]
+fn fetch_keyset(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    let query_str = l.check_string(2)?;
+    conn.check_query_length(&query_str)?;
+    let query_str = query_str.trim_end().trim_end_matches(';');
+    if query_str.matches(KEYSET_SLOT).count() != 1 {
+        bail!("query must contain {} exactly once", KEYSET_SLOT);
+    }
+    let key_column = l.check_string(3)?.into_owned();
+    // nil for the first page
+    let after = match l.is_none_or_nil(4) {
+        true => None,
+        false => {
+            l.push_value(4);
+            let param = query::to_param(l, "after");
+            l.pop();
+            Some(param?)
+        }
+    };
+    let limit = positive_integer("limit", l.check_number(5)?)?;
+    // checked before parsing so a callback reference isn't leaked, the key can't be bound in a raw query
+    reject_raw(l, 6, "FetchKeyset")?;
+
+    let mut query = query::Query::new(query_str.to_string(), query::QueryType::FetchAll);
+    query.parse_callback_or_options(l, 6)?;
+
+    apply_keyset(&mut query, &key_column, after);
+    query.query.push_str(" LIMIT ?");
+    query
+        .params
+        .push(query::param::Param::BigUnsigned(limit));
+    // the result has the column without its table
+    let key_name = query::escape::unqualified(&key_column).to_string();
+    query.keyset = Some((key_name, limit as usize));
+
+    run_query(l, conn, query, traceback)
+}
+
+// conn:FetchByKey(table, {id = 1, server = "eu"}, callback_or_options)
+#[lua_function]
+fn fetch_by_key(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    let table = l.check_string(2)?.into_owned();
+    l.check_table(3)?;
+    let mut keys = Vec::new();
+    l.push_nil();
+    while l.next(3) != 0 {
+        let column = match l.lua_type(-2) {
+            LUA_TSTRING => l.get_string_unchecked(-2).into_owned(),
+            _ => {
+                l.pop_n(2);
+                bail!("key columns must be strings");
+            }
+        };
+        let param = query::to_param(l, &column);
+        l.pop();
+        match param {
+            Ok(param) => keys.push((column, param)),
+            Err(e) => {
+                l.pop();
+                return Err(e);
+            }
+        }
+    }
+    if keys.is_empty() {
+        bail!("key table can't be empty");
+    }
+    // pairs order isn't stable, sorted so the same key columns always make the same statement
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    reject_raw(l, 4, "FetchByKey")?;
+
+    let conditions = keys
+        .iter()
+        .map(|(column, _)| format!("{} = ?", query::escape::identifier(column)))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let query_str = format!(
+        "SELECT * FROM {} WHERE {} LIMIT 1",
+        query::escape::qualified(&table),
+        conditions
+    );
+
+    let mut query = query::Query::new(query_str, query::QueryType::FetchOne);
+    query.parse_callback_or_options(l, 4)?;
+    // the key values are the params
+    query.params = keys.into_iter().map(|(_, param)| param).collect();
+
+    run_query(l, conn, query, traceback)
+}
+
+#[lua_function]
+fn fetch_chunked(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata(l)?;
+    chunked::new(l, conn)
+}
+
+#[lua_function]
+fn open_cursor(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata(l)?;
+    cursor::new(l, conn)
+}
+
+#[lua_function]
+fn fetch_to_file(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata(l)?;
+    export::new(l, conn)
+}
+
+#[lua_function]
+fn use_database(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    let name = l.check_string(2)?;
+    let mut query = use_database_query(&name);
+    query.parse_callback_or_options(l, 3)?;
+    query.raw = true;
+
+    run_query(l, conn, query, traceback)
+}
+
+// USE can't be prepared, so it has to go through as a raw query
+fn use_database_query(name: &str) -> query::Query {
+    let query_str = format!("USE {}", query::escape::identifier(name));
+    let mut query = query::Query::new(query_str, query::QueryType::Execute);
+    query.raw = true;
+    query.use_database = Some(name.to_string());
+    query
+}
+
+#[lua_function]
+fn server_status(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    // names are optional, ServerStatus(cb) or ServerStatus({"Uptime", "Queries"}, cb)
+    let mut arg_n = 2;
+    let mut names = Vec::new();
+    if l.lua_type(arg_n) == LUA_TTABLE {
+        for i in 1..=l.len(arg_n) {
+            l.raw_geti(arg_n, i);
+            names.push(l.check_string(-1)?.into_owned());
+            l.pop();
+        }
+        arg_n += 1;
+    }
+
+    let query_str = if names.is_empty() {
+        "SHOW GLOBAL STATUS".to_string()
+    } else {
+        let placeholders = vec!["?"; names.len()].join(", ");
+        format!(
+            "SELECT VARIABLE_NAME, VARIABLE_VALUE FROM performance_schema.global_status WHERE VARIABLE_NAME IN ({})",
+            placeholders
+        )
+    };
+
+    let mut query = query::Query::new(query_str, query::QueryType::FetchAll);
+    query.parse_callback_or_options(l, arg_n)?;
+    query.raw = names.is_empty();
+    query.params = names
+        .into_iter()
+        .map(|name| query::param::Param::String(name.into_bytes()))
+        .collect();
+    query.key_value = true;
+
+    run_query(l, conn, query, traceback)
+}
+
+// nil if the table doesn't exist in the current database or has no AUTO_INCREMENT column
+#[lua_function]
+fn next_auto_increment(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    let table = l.check_string(2)?.into_owned();
+    // mysql's limit for identifiers, anything longer can't be a table
+    if table.is_empty() || table.chars().count() > 64 {
+        bail!("invalid table name: {}", table);
+    }
+
+    let mut query = auto_increment_query();
+    query.parse_callback_or_options(l, 3)?;
+    // the table name is bound, not put in the query
+    query.raw = false;
+    query.params = vec![query::param::Param::String(table.into_bytes())];
+    // the session variable below has to be set on the connection the query runs on
+    if query.background {
+        bail!("NextAutoIncrement can't run in the background");
+    }
+    query.max_result_bytes = conn.connect_options.max_result_bytes;
+
+    if query.sync {
+        conn.check_no_open_cursor()?;
+        let (mut query, res) = wait_async(l, async move {
+            let res = next_auto_increment_inner(conn, &mut query).await;
+            (query, res)
+        });
+        return Ok(query.process_result(l, res, None));
+    }
+
+    run_async(async move {
+        let res = next_auto_increment_inner(conn, &mut query).await;
+        wait_lua_tick(traceback.clone(), move |l| {
+            query.process_result(l, res, Some(&traceback));
+        });
+    });
+
+    Ok(0)
+}
+
+fn auto_increment_query() -> query::Query {
+    let query_str = "SELECT AUTO_INCREMENT FROM information_schema.TABLES WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?";
+    query::Query::new(query_str.to_string(), query::QueryType::FetchValue)
+}
+
+// mysql 8 answers information_schema.TABLES from a statistics cache that can be a day old,
+// mariadb and 5.7 don't cache it and don't have the variable either
+async fn next_auto_increment_inner(
+    conn: Arc<Conn>,
+    query: &mut query::Query,
+) -> Result<query::QueryResult> {
+    let mut no_cache = query::Query::new(
+        "SET SESSION information_schema_stats_expiry = 0".to_string(),
+        query::QueryType::Execute,
+    );
+    no_cache.raw = true;
+    no_cache.high_priority = query.high_priority;
+    match internal_query(conn.clone(), &mut no_cache).await {
+        // unknown system variable
+        Err(e) if mysql_error_number(&e) == Some(1193) => {}
+        res => {
+            res?;
+        }
+    }
+    internal_query(conn, query).await
+}
+
+#[lua_function]
+fn escape(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata_no_lock(l)?;
+    l.check_string(2)?;
+    let s = l.get_binary_string(2).unwrap_or_default();
+    if l.is_none_or_nil(3) {
+        l.push_binary_string(&conn.escape_tracked(s));
+        return Ok(1);
+    }
+
+    ensure_running()?;
+    let traceback = l.get_traceback(l, 1).into_owned();
+    l.check_function(3)?;
+    l.push_value(3);
+    let callback = l.reference();
+    let s = s.to_vec();
+
+    run_async(async move {
+        let escaped = conn.escape(&s).await;
+        wait_lua_tick(traceback, move |l| {
+            l.push_binary_string(&escaped);
+            l.pcall_ignore_function_ref(callback, 1, 0);
+            l.dereference(callback);
+        });
+    });
+
+    Ok(0)
+}
+
+#[lua_function]
+fn prefixed(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata_no_lock(l)?;
+    let name = l.check_string(2)?;
+    let table = format!("{}{}", conn.connect_options.table_prefix, name);
+    l.push_string(&query::escape::identifier(&table));
+    Ok(1)
+}
+
 #[lua_function]
 fn is_connected(l: lua::State) -> Result<i32> {
     let conn = Conn::extract_userdata_no_lock(l)?;
@@ -399,9 +1442,31 @@ fn get_state(l: lua::State) -> Result<i32> {
     Ok(1)
 }
 
+#[lua_function]
+fn on_state_change(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata_no_lock(l)?;
+
+    // nil removes the callback
+    let callback = if l.is_none_or_nil(2) {
+        LUA_NOREF
+    } else {
+        l.check_function(2)?;
+        l.push_value(2);
+        l.reference()
+    };
+
+    let old_callback = conn
+        .on_state_change
+        .swap(callback, Ordering::AcqRel);
+    l.dereference(old_callback);
+
+    Ok(0)
+}
+
 #[lua_function]
 fn ping(l: lua::State) -> Result<i32> {
     let conn = Conn::extract_userdata(l)?;
+    conn.check_no_open_cursor()?;
 
     let res = wait_async(l, async move { conn.ping().await });
     match res {
@@ -417,6 +1482,103 @@ fn ping(l: lua::State) -> Result<i32> {
     }
 }
 
+#[lua_function]
+fn healthcheck(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+    l.check_function(2)?;
+    l.push_value(2);
+    let callback = l.reference();
+
+    run_async(async move {
+        let res = conn.healthcheck().await;
+        wait_lua_tick(traceback.clone(), move |l| {
+            l.create_table(0, 4);
+            {
+                l.push_bool(res.is_ok());
+                l.set_field(-2, c"healthy");
+
+                l.push_number(conn.state() as i32);
+                l.set_field(-2, c"state");
+
+                match res {
+                    Ok(latency) => {
+                        l.push_number(latency.as_secs_f64() * 1000.0);
+                        l.set_field(-2, c"latency");
+                    }
+                    Err(e) => {
+                        handle_error(l, e);
+                        l.set_field(-2, c"error");
+                    }
+                }
+            }
+
+            l.pcall_ignore_function_ref(callback, 1, 0);
+            l.dereference(callback);
+        });
+    });
+
+    Ok(0)
+}
+
+#[lua_function]
+fn tls_info(l: lua::State) -> Result<i32> {
+    let conn = Conn::extract_userdata_no_lock(l)?;
+    let tls_info = conn.tls_info.lock().unwrap().clone();
+    match tls_info {
+        Some(tls_info) => {
+            l.create_table(0, 3);
+            {
+                l.push_bool(!tls_info.cipher.is_empty());
+                l.set_field(-2, c"enabled");
+
+                l.push_string(&tls_info.cipher);
+                l.set_field(-2, c"cipher");
+
+                l.push_string(&tls_info.version);
+                l.set_field(-2, c"version");
+            }
+        }
+        None => l.push_nil(),
+    }
+    Ok(1)
+}
+
+#[lua_function]
+fn kill_current_query(l: lua::State) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+    let conn = Conn::extract_userdata(l)?;
+
+    let callback = if l.is_none_or_nil(2) {
+        LUA_NOREF
+    } else {
+        l.check_function(2)?;
+        l.push_value(2);
+        l.reference()
+    };
+
+    run_async(async move {
+        let res = conn.kill_current_query().await;
+        wait_lua_tick(traceback.clone(), move |l| {
+            let (returns_count, err_msg) = match res {
+                Ok(_) => (0, None),
+                Err(e) => (1, Some(handle_error(l, e))),
+            };
+
+            let (called_function, _) = l.pcall_ignore_function_ref(callback, returns_count, 0);
+            if !called_function {
+                if let Some(err_msg) = err_msg {
+                    l.error_no_halt(&err_msg, Some(&traceback));
+                }
+            }
+
+            l.dereference(callback);
+        });
+    });
+
+    Ok(0)
+}
+
 #[lua_function]
 fn __tostring(l: lua::State) -> Result<i32> {
     let conn = Conn::extract_userdata_no_lock(l)?;
@@ -434,6 +1596,14 @@ fn __gc(l: lua::State) -> Result<i32> {
         }
     };
 
+    registry::remove(conn.id);
+
+    // cleared before disconnecting so the disconnect below doesn't call into a dead reference
+    let on_state_change = conn
+        .on_state_change
+        .swap(LUA_NOREF, Ordering::AcqRel);
+    let on_connect = conn.on_connect.swap(LUA_NOREF, Ordering::AcqRel);
+
     let ConnectOptions {
         on_connected,
         on_error,
@@ -471,6 +1641,206 @@ fn __gc(l: lua::State) -> Result<i32> {
     l.dereference(on_connected);
     l.dereference(on_error);
     l.dereference(on_disconnected);
+    l.dereference(on_connect);
+    l.dereference(on_state_change);
 
     Ok(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::Executor as _;
+
+    use super::{
+        auto_increment_query, latest_deadlock_section, next_auto_increment_inner, positive_integer,
+        query_on_conn, script_pipeline, use_database_query, Conn, ConnectOptions,
+    };
+    use crate::{
+        query::{param::Param, QueryResult},
+        test_db,
+    };
+
+    fn conn_with_max_query_length(max_length: usize) -> Conn {
+        let mut opts = ConnectOptions::new();
+        opts.max_query_length = Some(max_length);
+        Conn::new(opts, String::new())
+    }
+
+    #[test]
+    fn long_queries_are_rejected() {
+        let conn = conn_with_max_query_length(16);
+        assert!(conn.check_query_length("SELECT 1").is_ok());
+        assert!(conn
+            .check_query_length("SELECT 123456789")
+            .is_ok());
+        let e = conn
+            .check_query_length("SELECT 12345678901")
+            .unwrap_err();
+        assert_eq!(test_db::kind(&e), Some("query_too_long"));
+
+        // unlimited by default
+        let conn = Conn::new(ConnectOptions::new(), String::new());
+        assert!(conn
+            .check_query_length(&"1".repeat(1 << 20))
+            .is_ok());
+    }
+
+    #[test]
+    fn every_script_statement_is_checked() {
+        let conn = conn_with_max_query_length(16);
+        assert_eq!(
+            script_pipeline(&conn, "SELECT 1; SELECT 2;")
+                .unwrap()
+                .len(),
+            2
+        );
+        let e = script_pipeline(&conn, "SELECT 1; SELECT 12345678901;").unwrap_err();
+        assert_eq!(test_db::kind(&e), Some("query_too_long"));
+    }
+
+    #[test]
+    fn finds_the_deadlock_section() {
+        let status = "=====\nINNODB MONITOR OUTPUT\n------------------------\nLATEST DETECTED DEADLOCK\n------------------------\n*** (1) TRANSACTION:\nUPDATE a\n*** (2) TRANSACTION:\nUPDATE b\n------------\nTRANSACTIONS\n------------\nTrx id counter 1\n";
+        let section = latest_deadlock_section(status).unwrap();
+        assert!(section.starts_with("LATEST DETECTED DEADLOCK"));
+        assert!(section.ends_with("UPDATE b"));
+        assert!(!section.contains("Trx id counter"));
+        assert_eq!(latest_deadlock_section("no deadlocks here"), None);
+    }
+
+    #[test]
+    fn limits_must_be_exact_positive_integers() {
+        assert_eq!(positive_integer("limit", 25.0).unwrap(), 25);
+        assert_eq!(
+            positive_integer("limit", (1u64 << 53) as f64).unwrap(),
+            1 << 53
+        );
+        for n in [0.0, -1.0, 1.5, f64::NAN, f64::INFINITY, 1e300] {
+            assert!(positive_integer("limit", n).is_err(), "{}", n);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn reconnect_stays_on_the_used_database() {
+        let database = test_db::database();
+        test_db::run(async move {
+            let mut opts = ConnectOptions::new();
+            opts.inner = test_db::url_without_database().parse().unwrap();
+            let conn = Conn::new(opts, String::new());
+            conn.start().await.unwrap();
+
+            query_on_conn(&conn, &mut use_database_query(&database))
+                .await
+                .unwrap();
+            // a reconnect has to end up where UseDatabase left it, not back on no database
+            conn.start().await.unwrap();
+
+            let mut inner = conn.inner.lock().await;
+            let row = inner
+                .as_mut()
+                .unwrap()
+                .fetch_one("SELECT DATABASE()")
+                .await
+                .unwrap();
+            let current: Option<String> = sqlx::Row::try_get(&row, 0).unwrap();
+            assert_eq!(current, Some(database));
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn next_auto_increment_is_current() {
+        test_db::run(async {
+            let mut opts = ConnectOptions::new();
+            opts.inner = test_db::options();
+            let conn = Arc::new(Conn::new(opts, String::new()));
+            conn.start().await.unwrap();
+
+            let next_id = || async {
+                let mut query = auto_increment_query();
+                query.params = vec![Param::String(b"goobie_auto_increment".to_vec())];
+                match next_auto_increment_inner(conn.clone(), &mut query)
+                    .await
+                    .unwrap()
+                {
+                    QueryResult::Row(row) => sqlx::Row::get::<u64, _>(&row.unwrap(), 0),
+                    _ => unreachable!(),
+                }
+            };
+
+            let mut inner = test_db::connect().await;
+            inner
+                .execute("DROP TABLE IF EXISTS goobie_auto_increment")
+                .await
+                .unwrap();
+            inner
+                .execute("CREATE TABLE goobie_auto_increment (id INT AUTO_INCREMENT PRIMARY KEY)")
+                .await
+                .unwrap();
+            // read once so mysql 8 has the value cached, inserts after that don't update it
+            assert_eq!(next_id().await, 1);
+            inner
+                .execute("INSERT INTO goobie_auto_increment VALUES (), (), ()")
+                .await
+                .unwrap();
+            assert_eq!(next_id().await, 4);
+
+            inner
+                .execute("DROP TABLE goobie_auto_increment")
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn escape_follows_the_session_charset() {
+        test_db::run(async {
+            let mut opts = ConnectOptions::new();
+            opts.inner = test_db::options();
+            let conn = Conn::new(opts, String::new());
+            conn.start().await.unwrap();
+
+            // 0xbf5c is a single gbk character, in utf8mb4 the 0x5c is a backslash that needs escaping
+            let crafted = b"\xbf\\' OR 1=1 -- ";
+            assert_eq!(conn.escape(crafted).await, b"\xbf\\\\\\' OR 1=1 -- ");
+
+            // changed behind the tracking's back, the callback form still sees it
+            conn.inner
+                .lock()
+                .await
+                .as_mut()
+                .unwrap()
+                .execute("SET NAMES gbk")
+                .await
+                .unwrap();
+            assert_eq!(conn.escape(crafted).await, b"\xbf\\\\' OR 1=1 -- ");
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn compress_connects_without_compression() {
+        test_db::run(async {
+            let mut opts = ConnectOptions::new();
+            opts.inner = test_db::options();
+            opts.compress = true;
+            let conn = Conn::new(opts, String::new());
+            conn.start().await.unwrap();
+            assert!(conn.connect_options.compress);
+
+            let mut inner = conn.inner.lock().await;
+            let row = inner
+                .as_mut()
+                .unwrap()
+                .fetch_one("SHOW SESSION STATUS LIKE 'Compression'")
+                .await
+                .unwrap();
+            let compression: String = sqlx::Row::try_get_unchecked(&row, 1).unwrap();
+            assert_eq!(compression, "OFF");
+        });
+    }
+}
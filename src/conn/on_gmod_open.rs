@@ -4,6 +4,7 @@ use crate::GLOBAL_TABLE_NAME_C;
 
 const CONNECT_METHODS: &[LuaReg] = lua_regs![
     "NewConn" => super::new,
+    "Connections" => super::registry::connections,
 ];
 
 pub fn init(l: lua::State) {
@@ -19,6 +20,12 @@ pub fn init(l: lua::State) {
     }
     l.pop();
 
+    super::registry::setup(l);
     super::state::setup(l);
     super::transaction::setup(l);
+    super::cursor::setup(l);
+}
+
+pub fn on_gmod_close(l: lua::State) {
+    super::transaction::clear_coroutine_pool(l);
 }
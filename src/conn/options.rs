@@ -1,13 +1,33 @@
+use std::time::Duration;
+
 use anyhow::{bail, Result};
 use gmod::{lua::*, *};
 use sqlx::mysql::MySqlConnectOptions;
 
+use crate::{error::KindError, print_goobie, CONNECT_RETRY_DELAY, MAX_CONNECT_RETRY_DELAY};
+
 #[derive(Debug, Clone)]
 pub struct Options {
     pub inner: MySqlConnectOptions,
     pub on_connected: i32,
     pub on_error: i32,
     pub on_disconnected: i32,
+    pub on_connect: i32,
+    pub max_result_bytes: Option<usize>,
+    pub max_query_length: Option<usize>,
+    pub debug_sql: bool,
+    pub sql_mode: Option<String>,
+    pub wait_timeout: Option<u32>,
+    pub interactive_timeout: Option<u32>,
+    pub warmup_statements: Vec<String>,
+    pub require_utf8mb4: bool,
+    pub ping_checks_database: bool,
+    pub init_timeout: Option<Duration>,
+    pub connect_retries: u32,
+    pub connect_retry_delay: Duration,
+    // inert until sqlx supports protocol compression, nothing reads it yet
+    pub compress: bool,
+    pub table_prefix: String,
 }
 
 impl Options {
@@ -17,6 +37,21 @@ impl Options {
             on_connected: LUA_NOREF,
             on_error: LUA_NOREF,
             on_disconnected: LUA_NOREF,
+            on_connect: LUA_NOREF,
+            max_result_bytes: None,
+            max_query_length: None,
+            debug_sql: false,
+            sql_mode: None,
+            wait_timeout: None,
+            interactive_timeout: None,
+            warmup_statements: Vec::new(),
+            require_utf8mb4: false,
+            ping_checks_database: false,
+            init_timeout: None,
+            connect_retries: 0,
+            connect_retry_delay: CONNECT_RETRY_DELAY,
+            compress: false,
+            table_prefix: String::new(),
         }
     }
 
@@ -50,7 +85,10 @@ impl Options {
 
     fn parse_uri(&mut self, l: lua::State, idx: i32) -> Result<()> {
         let uri = l.get_string_unchecked(idx);
-        self.inner = uri.parse()?;
+        self.inner = match uri.parse() {
+            Ok(opts) => opts,
+            Err(e) => bail!("invalid connection uri: {}", e),
+        };
         Ok(())
     }
 
@@ -67,6 +105,10 @@ impl Options {
             self.on_disconnected = l.reference();
         }
 
+        if l.get_field_type_or_nil(arg_n, c"on_connect", LUA_TFUNCTION)? {
+            self.on_connect = l.reference();
+        }
+
         Ok(())
     }
 
@@ -110,8 +152,6 @@ impl Options {
                 let db = l.get_string_unchecked(-1);
                 self.inner = self.inner.clone().database(db.as_ref());
                 l.pop();
-            } else {
-                bail!("Database name is required!");
             }
 
             // self.uri = connect_options.build()?;
@@ -133,6 +173,26 @@ impl Options {
             l.pop();
         }
 
+        // the charset is checked again after connecting, the server can still pick another one
+        if l.get_field_type_or_nil(arg_n, c"require_utf8mb4", LUA_TBOOLEAN)? {
+            self.require_utf8mb4 = l.get_boolean(-1);
+            l.pop();
+            if self.require_utf8mb4 && self.inner.get_charset() != "utf8mb4" {
+                bail!(
+                    "require_utf8mb4 can't be used with charset {}",
+                    self.inner.get_charset()
+                );
+            }
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"ping_checks_database", LUA_TBOOLEAN)? {
+            self.ping_checks_database = l.get_boolean(-1);
+            l.pop();
+            if self.ping_checks_database && self.inner.get_database().is_none() {
+                bail!("ping_checks_database needs a database to check against");
+            }
+        }
+
         if l.get_field_type_or_nil(arg_n, c"timezone", LUA_TSTRING)? {
             let timezone = l.get_string_unchecked(-1).into_owned();
             self.inner = self.inner.clone().timezone(timezone);
@@ -148,6 +208,158 @@ impl Options {
             l.pop();
         }
 
+        if l.get_field_type_or_nil(arg_n, c"warmup_statements", LUA_TTABLE)? {
+            for i in 1..=l.len(-1) {
+                l.raw_geti(-1, i);
+                let sql = l.check_string(-1)?.into_owned();
+                self.warmup_statements.push(sql);
+                l.pop();
+            }
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"max_result_bytes", LUA_TNUMBER)? {
+            let max_bytes = l.to_number(-1);
+            l.pop();
+            self.max_result_bytes = Some(positive_size("max_result_bytes", max_bytes)?);
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"max_query_length", LUA_TNUMBER)? {
+            let max_length = l.to_number(-1);
+            l.pop();
+            self.max_query_length = Some(positive_size("max_query_length", max_length)?);
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"debug_sql", LUA_TBOOLEAN)? {
+            self.debug_sql = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"sql_mode", LUA_TSTRING)? {
+            let sql_mode = l.get_string_unchecked(-1).into_owned();
+            l.pop();
+            if sql_mode.trim().is_empty() {
+                bail!("sql_mode can't be empty!");
+            }
+            self.sql_mode = Some(sql_mode);
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"wait_timeout", LUA_TNUMBER)? {
+            let wait_timeout = l.to_number(-1);
+            l.pop();
+            if wait_timeout < 1.0 || wait_timeout.fract() != 0.0 {
+                bail!("wait_timeout must be a positive integer!");
+            }
+            self.wait_timeout = Some(wait_timeout as u32);
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"interactive_timeout", LUA_TNUMBER)? {
+            let interactive_timeout = l.to_number(-1);
+            l.pop();
+            if interactive_timeout < 1.0 || interactive_timeout.fract() != 0.0 {
+                bail!("interactive_timeout must be a positive integer!");
+            }
+            self.interactive_timeout = Some(interactive_timeout as u32);
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"init_timeout", LUA_TNUMBER)? {
+            let init_timeout = l.to_number(-1);
+            l.pop();
+            self.init_timeout = Some(positive_duration("init_timeout", init_timeout)?);
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"connect_retries", LUA_TNUMBER)? {
+            let connect_retries = l.to_number(-1);
+            l.pop();
+            if connect_retries < 0.0 || connect_retries.fract() != 0.0 {
+                bail!("connect_retries must be a non-negative integer!");
+            }
+            self.connect_retries = connect_retries as u32;
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"connect_retry_delay", LUA_TNUMBER)? {
+            let connect_retry_delay = l.to_number(-1);
+            l.pop();
+            self.connect_retry_delay =
+                positive_duration("connect_retry_delay", connect_retry_delay)?
+                    .min(MAX_CONNECT_RETRY_DELAY);
+        }
+
+        // sqlx opens the socket itself and there is no way to hand it a bound one, connecting from
+        // another address than asked for could get the connection rejected by host based grants
+        if l.get_field_type_or_nil(arg_n, c"source_address", LUA_TSTRING)? {
+            l.pop();
+            return Err(KindError::new(
+                "unsupported_option",
+                "source_address is not supported, sqlx can't bind the socket to a local address",
+            )
+            .into());
+        }
+
+        // sqlx doesn't send connection attributes in the handshake, and they can't be set after
+        // connecting, monitoring would silently miss them so it's an error instead
+        if l.get_field_type_or_nil(arg_n, c"connect_attributes", LUA_TTABLE)? {
+            l.pop();
+            return Err(KindError::new(
+                "unsupported_option",
+                "connect_attributes is not supported, sqlx doesn't send connection attributes",
+            )
+            .into());
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"table_prefix", LUA_TSTRING)? {
+            self.table_prefix = l.get_string_unchecked(-1).into_owned();
+            l.pop();
+        }
+
+        // sqlx doesn't implement the compressed protocol yet, kept so it can be passed through once it does
+        if l.get_field_type_or_nil(arg_n, c"compress", LUA_TBOOLEAN)? {
+            self.compress = l.get_boolean(-1);
+            l.pop();
+            if self.compress {
+                print_goobie!(
+                    "compress option is not supported yet, connecting without compression"
+                );
+            }
+        }
+
         Ok(())
     }
 }
+
+// lua numbers can be inf or nan, Duration::from_secs_f64 panics on those and a panic aborts the server
+fn positive_duration(name: &str, secs: f64) -> Result<Duration> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(KindError::new(
+            "invalid_option",
+            format!("{} must be a finite number of seconds greater than 0", name),
+        )
+        .into()),
+    }
+}
+
+// a 0 or a truncated fraction would make every query trip the limit
+fn positive_size(name: &str, n: f64) -> Result<usize> {
+    if n < 1.0 || n.fract() != 0.0 {
+        bail!("{} must be a positive integer", name);
+    }
+    Ok(n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::positive_size;
+
+    #[test]
+    fn sizes_must_be_positive_integers() {
+        assert_eq!(positive_size("max_result_bytes", 1.0).unwrap(), 1);
+        assert_eq!(
+            positive_size("max_result_bytes", 1048576.0).unwrap(),
+            1048576
+        );
+        for n in [0.0, -1.0, 0.5, 1.5, f64::NAN, f64::INFINITY] {
+            assert!(positive_size("max_result_bytes", n).is_err(), "{}", n);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use gmod::*;
+
+use super::Conn;
+
+// table with weak values, a connection is dropped out of it before its __gc runs
+// (weak keys would keep it around until the next collection, after it was already freed)
+static REGISTRY_REF: AtomicI32 = AtomicI32::new(LUA_NOREF);
+static NEXT_ID: AtomicI32 = AtomicI32::new(1);
+
+// the lua table only has the handles, this keeps them in the order they were created and is what
+// __gc takes them out of
+static CONNECTIONS: Mutex<BTreeMap<i32, Weak<Conn>>> = Mutex::new(BTreeMap::new());
+
+pub fn setup(l: lua::State) {
+    l.create_table(0, 0);
+    l.create_table(0, 1);
+    {
+        l.push_string("v");
+        l.set_field(-2, c"__mode");
+    }
+    unsafe { l.set_metatable(-2) };
+
+    // lua state is new on every map change, the old reference went away with it
+    REGISTRY_REF.store(l.reference(), Ordering::Release);
+    CONNECTIONS.lock().unwrap().clear();
+}
+
+pub fn next_id() -> i32 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn register(conn: &Arc<Conn>) {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .insert(conn.id, Arc::downgrade(conn));
+}
+
+// called from __gc, the connection can outlive its handle while it's disconnecting
+pub fn remove(id: i32) {
+    CONNECTIONS.lock().unwrap().remove(&id);
+}
+
+// in the order they were created
+fn live() -> Vec<Arc<Conn>> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+// connection userdata has to be at the top of the stack
+pub fn add(l: lua::State, conn: &Arc<Conn>) {
+    register(conn);
+    l.from_reference(REGISTRY_REF.load(Ordering::Acquire));
+    {
+        l.push_value(-2);
+        l.raw_seti(-2, conn.id);
+    }
+    l.pop();
+}
+
+#[lua_function]
+pub fn connections(l: lua::State) -> i32 {
+    let conns = live();
+
+    l.create_table(conns.len() as i32, 0);
+    l.from_reference(REGISTRY_REF.load(Ordering::Acquire));
+
+    let mut idx = 0;
+    for conn in conns {
+        l.raw_geti(-1, conn.id);
+        // collected, its __gc just didn't run yet
+        if l.is_nil(-1) {
+            l.pop();
+            continue;
+        }
+
+        l.create_table(0, 5);
+        {
+            l.push_value(-2);
+            l.set_field(-2, c"conn");
+
+            l.push_number(conn.id);
+            l.set_field(-2, c"id");
+
+            l.push_string(conn.connect_options.inner.get_host());
+            l.set_field(-2, c"host");
+
+            l.push_number(conn.connect_options.inner.get_port());
+            l.set_field(-2, c"port");
+
+            l.push_number(conn.state() as i32);
+            l.set_field(-2, c"state");
+        }
+        idx += 1;
+        l.raw_seti(-4, idx); // pops the snapshot
+        l.pop(); // pop the connection
+    }
+    l.pop(); // pop the registry table
+
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{live, register, remove};
+    use crate::conn::{Conn, ConnectOptions};
+
+    #[test]
+    fn connections_are_listed_until_collected() {
+        let first = Arc::new(Conn::new(ConnectOptions::new(), String::new()));
+        let second = Arc::new(Conn::new(ConnectOptions::new(), String::new()));
+        register(&first);
+        register(&second);
+
+        let ids = |conns: Vec<Arc<Conn>>| {
+            conns
+                .iter()
+                .map(|conn| conn.id)
+                .filter(|id| [first.id, second.id].contains(id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(live()), [first.id, second.id]);
+
+        // what __gc does when lua lets go of the handle
+        remove(first.id);
+        assert_eq!(ids(live()), [second.id]);
+
+        // dropped without going through __gc (e.g. the lua state closed), still not listed
+        let second_id = second.id;
+        drop(second);
+        assert!(!live().iter().any(|conn| conn.id == second_id));
+    }
+}
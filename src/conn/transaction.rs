@@ -6,16 +6,54 @@ use sqlx::{Connection as _, Executor, MySqlConnection};
 use tokio::sync::{Mutex, OwnedMutexGuard};
 
 use crate::{
+    constants::TRANSACTION_COROUTINE_POOL_SIZE,
     cstr_from_args,
-    error::{handle_error, handle_sqlx_error},
-    query::{Query, QueryType},
-    run_async, wait_async, GLOBAL_TABLE_NAME,
+    error::{handle_error, handle_sqlx_error, is_cancelled, is_connection_lost, KindError},
+    query::{escape, Query, QueryResult, QueryType},
+    run_async,
+    runtime::ensure_running,
+    wait_async, GLOBAL_TABLE_NAME,
 };
 
-use super::Conn;
+use super::{state::State, Conn};
 
 const META_NAME: LuaCStr = cstr_from_args!(GLOBAL_TABLE_NAME, "_transaction");
 
+// coroutines of transactions that finished cleanly, a finished coroutine can run a new function
+// lua only ever touches this from the main thread, the mutex is just to keep it a safe static
+static COROUTINE_POOL: std::sync::Mutex<CoroutinePool> =
+    std::sync::Mutex::new(CoroutinePool::new());
+
+struct CoroutinePool(Vec<i32>);
+
+impl CoroutinePool {
+    const fn new() -> Self {
+        CoroutinePool(Vec::new())
+    }
+
+    fn take(&mut self) -> Option<i32> {
+        self.0.pop()
+    }
+
+    // only a coroutine whose function returned can be given a new one, an errored one is dead for good
+    // and a yielded one is still in the middle of its function. false means the ref wasn't kept and
+    // has to be released
+    fn put_back(&mut self, co_ref: i32, status: i32) -> bool {
+        if status != LUA_OK || self.0.len() >= TRANSACTION_COROUTINE_POOL_SIZE {
+            return false;
+        }
+        // released twice would hand the same coroutine to two transactions
+        if !self.0.contains(&co_ref) {
+            self.0.push(co_ref);
+        }
+        true
+    }
+
+    fn drain(&mut self) -> Vec<i32> {
+        std::mem::take(&mut self.0)
+    }
+}
+
 pub const METHODS: &[LuaReg] = lua_regs![
     "IsOpen" => is_open,
     "Ping" => ping,
@@ -23,6 +61,12 @@ pub const METHODS: &[LuaReg] = lua_regs![
     "Execute" => execute,
     "FetchOne" => fetch_one,
     "Fetch" => fetch,
+    "FetchValue" => fetch_value,
+
+    "Savepoint" => savepoint,
+    "RollbackTo" => rollback_to,
+    "Release" => release,
+    "Depth" => depth,
 
     "Commit" => commit,
     "Rollback" => rollback,
@@ -31,6 +75,9 @@ pub const METHODS: &[LuaReg] = lua_regs![
 ];
 
 pub fn setup(l: lua::State) {
+    // gmod13_close should have emptied it, refs from an older lua state must never be handed out
+    COROUTINE_POOL.lock().unwrap().drain();
+
     // let meta_name = GLOBAL_TABLE_NAME_C.concat(META_NAME);
     l.new_metatable(META_NAME);
     {
@@ -64,6 +111,15 @@ enum Action {
     Rollback,
 }
 
+// applied to the savepoint stack once the statement went through, positions are into the stack
+#[derive(Debug)]
+enum SavepointAction {
+    Set(String),
+    // rolling back keeps the savepoint itself, only the ones after it are gone
+    RollbackTo(usize),
+    Release(usize),
+}
+
 #[repr(C)]
 pub struct Transaction {
     conn: Arc<Conn>,
@@ -73,10 +129,17 @@ pub struct Transaction {
     sync: bool,
     finalizing: bool,
     traceback: String,
+    // names of the active savepoints, innermost last
+    savepoints: Vec<String>,
 }
 
 impl Transaction {
-    pub async fn new(conn: Arc<Conn>, coroutine_ref: i32, traceback: String) -> Result<Self> {
+    pub async fn new(
+        conn: Arc<Conn>,
+        coroutine_ref: i32,
+        traceback: String,
+        read_only: bool,
+    ) -> Result<Self> {
         let mut conn_guard = conn.inner.clone().lock_owned().await;
 
         {
@@ -87,9 +150,14 @@ impl Transaction {
                 }
             };
 
-            inner_conn
-                .execute("SET autocommit = 0; BEGIN;")
-                .await?;
+            // read only lets mysql skip some bookkeeping and makes any write fail
+            let begin = if read_only {
+                "SET autocommit = 0; START TRANSACTION READ ONLY;"
+            } else {
+                "SET autocommit = 0; BEGIN;"
+            };
+
+            inner_conn.execute(begin).await?;
         }
 
         Ok(Transaction {
@@ -100,6 +168,7 @@ impl Transaction {
             sync: false,
             finalizing: false,
             traceback,
+            savepoints: Vec::new(),
         })
     }
 
@@ -117,6 +186,8 @@ impl Transaction {
 
     #[inline]
     pub fn extract_userdata(l: lua::State) -> Result<Arc<Mutex<Self>>> {
+        ensure_running()?;
+
         let txn_ptr = l.get_userdata::<*const Mutex<Self>>(1, Some(META_NAME))?;
         let txn_ptr = *txn_ptr;
 
@@ -206,6 +277,76 @@ impl Transaction {
         res.map(|_| ())
     }
 
+    async fn run_query(&mut self, query: &mut Query) -> Result<QueryResult> {
+        let res = get_connection!(self.conn_guard, conn => {
+            let res = query.start(conn).await;
+            // a cancelled query left the connection mid result, it's dropped below anyway
+            let charset_restored = match query.take_charset_restore() {
+                Some(_) if matches!(&res, Err(e) if is_cancelled(e)) => true,
+                Some(restore) => conn.execute(restore.as_str()).await.is_ok(),
+                None => true,
+            };
+            if query.changes_escaping() {
+                self.conn.refresh_escape_session(conn).await;
+            }
+            (res, charset_restored)
+        });
+        let res = match res {
+            (res, true) => res,
+            // the rest of the transaction would run with the wrong charset
+            (res, false) => {
+                self.abort();
+                return res.and(Err(KindError::new(
+                    "transaction_aborted",
+                    "transaction aborted, the session's charset couldn't be restored",
+                )
+                .into()));
+            }
+        };
+        match res {
+            // the connection is unusable, the server rolls the transaction back once it's closed
+            Err(e) if is_cancelled(&e) => {
+                self.abort();
+                Err(e)
+            }
+            Err(e) if is_connection_lost(&e) => {
+                self.abort();
+                Err(KindError::new(
+                    "transaction_aborted",
+                    format!("transaction aborted due to connection loss: {}", e),
+                )
+                .into())
+            }
+            res => res,
+        }
+    }
+
+    fn apply_savepoint_action(&mut self, action: SavepointAction) {
+        match action {
+            SavepointAction::Set(name) => {
+                // mysql replaces a savepoint that has the same name
+                self.savepoints.retain(|n| *n != name);
+                self.savepoints.push(name);
+            }
+            SavepointAction::RollbackTo(idx) => self.savepoints.truncate(idx + 1),
+            SavepointAction::Release(idx) => self.savepoints.truncate(idx),
+        }
+    }
+
+    // there is nothing to roll back on a dead connection, just close the transaction and let go of it
+    fn abort(&mut self) {
+        self.set_open(false);
+
+        if let Some(mut conn_guard) = self.conn_guard.take() {
+            conn_guard.take();
+        }
+        self.conn.set_state(State::Error);
+
+        self.conn
+            .transaction_coroutine_ref
+            .store(LUA_NOREF, Ordering::Release);
+    }
+
     #[inline]
     pub fn is_open(&self) -> bool {
         self.open && !self.finalizing
@@ -227,27 +368,31 @@ impl Drop for Transaction {
     fn drop(&mut self) {
         let coroutine_ref = self.coroutine_ref;
         wait_lua_tick(self.traceback.clone(), move |l| {
-            l.dereference(coroutine_ref);
+            release_coroutine(l, coroutine_ref);
         });
     }
 }
 
-fn internal_new(l: lua::State, sync: bool) -> Result<i32> {
+fn internal_new(l: lua::State, sync: bool, read_only: bool) -> Result<i32> {
     let traceback = l.get_traceback(l, 1).into_owned();
     let conn = Conn::extract_userdata(l)?;
     l.check_function(2)?;
+    if sync {
+        conn.check_no_open_cursor()?;
+    }
 
-    // we create a coroutine and pass the function to it
-    let co = l.coroutine_new();
+    // we get a coroutine and pass the function to it
+    let co_ref = take_coroutine(l);
+    let co = get_coroutine(l, co_ref);
     l.push_value(2);
     l.coroutine_exchange(co, 1);
-    let co_ref = l.reference();
 
     // this is to avoid deadlock when someone mistakenly tries to run a sync conn:query while in a transaction
     conn.transaction_coroutine_ref
         .store(co_ref, Ordering::Release);
 
     let traceback_clone = traceback.clone();
+    let conn_clone = conn.clone();
     let handle_new_txn = move |l: lua::State, txn: Result<Transaction>| match txn {
         Ok(txn) => {
             let co = get_coroutine(l, co_ref);
@@ -265,16 +410,25 @@ fn internal_new(l: lua::State, sync: bool) -> Result<i32> {
             let co = get_coroutine(l, co_ref);
             handle_error(co, e);
             let _ = co.coroutine_resume_ignore(1, Some(&traceback_clone));
+
+            // no transaction was created to own the coroutine
+            conn_clone
+                .transaction_coroutine_ref
+                .store(LUA_NOREF, Ordering::Release);
+            release_coroutine(l, co_ref);
         }
     };
 
     let traceback = traceback.clone();
     if sync {
-        let res = wait_async(l, Transaction::new(conn, co_ref, traceback.clone()));
+        let res = wait_async(
+            l,
+            Transaction::new(conn, co_ref, traceback.clone(), read_only),
+        );
         handle_new_txn(l, res);
     } else {
         run_async(async move {
-            let res = Transaction::new(conn, co_ref, traceback.clone()).await;
+            let res = Transaction::new(conn, co_ref, traceback.clone(), read_only).await;
             wait_lua_tick(traceback.clone(), move |l| handle_new_txn(l, res));
         });
     }
@@ -284,12 +438,22 @@ fn internal_new(l: lua::State, sync: bool) -> Result<i32> {
 
 #[lua_function]
 pub fn new(l: lua::State) -> Result<i32> {
-    internal_new(l, false)
+    internal_new(l, false, false)
 }
 
 #[lua_function]
 pub fn new_sync(l: lua::State) -> Result<i32> {
-    internal_new(l, true)
+    internal_new(l, true, false)
+}
+
+#[lua_function]
+pub fn new_read_only(l: lua::State) -> Result<i32> {
+    internal_new(l, false, true)
+}
+
+#[lua_function]
+pub fn new_read_only_sync(l: lua::State) -> Result<i32> {
+    internal_new(l, true, true)
 }
 
 #[lua_function]
@@ -331,17 +495,35 @@ fn ping(l: lua::State) -> Result<i32> {
 }
 
 fn internal_query(l: lua::State, query_type: QueryType) -> Result<i32> {
-    let traceback = l.get_traceback(l, 1).into_owned();
-
     let txn_mutex = Transaction::extract_userdata(l)?;
-    let (mut query, is_sync, coroutine_ref) = {
-        let txn = txn_mutex.blocking_lock();
-
+    let query = {
         let query = l.check_string(2)?;
+        txn_mutex
+            .blocking_lock()
+            .conn
+            .check_query_length(&query)?;
         let mut query = Query::new(query.to_string(), query_type);
         query.parse_options(l, 3, false)?;
+        query
+    };
+
+    run_query(l, txn_mutex, query, None)
+}
 
-        (query, txn.sync, txn.coroutine_ref)
+fn run_query(
+    l: lua::State,
+    txn_mutex: Arc<Mutex<Transaction>>,
+    mut query: Query,
+    savepoint_action: Option<SavepointAction>,
+) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+
+    let (is_sync, coroutine_ref) = {
+        let txn = txn_mutex.blocking_lock();
+        query.max_result_bytes = txn.conn.connect_options.max_result_bytes;
+        query.debug_sql = txn.conn.connect_options.debug_sql;
+
+        (txn.sync, txn.coroutine_ref)
     };
 
     let txn_mutex_clone = txn_mutex.clone();
@@ -350,11 +532,10 @@ fn internal_query(l: lua::State, query_type: QueryType) -> Result<i32> {
         let res = wait_async(l, async move {
             let mut txn = txn_mutex_clone.lock().await;
 
-            let (res, query) = get_connection!(txn.conn_guard, conn => {
-                let res = query.start(conn).await ;
-                (res, query)
-            });
-
+            let res = txn.run_query(&mut query).await;
+            if let (Ok(_), Some(action)) = (&res, savepoint_action) {
+                txn.apply_savepoint_action(action);
+            }
             (res, query)
         });
 
@@ -365,9 +546,10 @@ fn internal_query(l: lua::State, query_type: QueryType) -> Result<i32> {
     run_async(async move {
         let res = {
             let mut txn = txn_mutex_clone.lock().await;
-            let (res, query) =
-                get_connection!(txn.conn_guard, conn => (query.start(conn).await, query));
-
+            let res = txn.run_query(&mut query).await;
+            if let (Ok(_), Some(action)) = (&res, savepoint_action) {
+                txn.apply_savepoint_action(action);
+            }
             (res, query)
         };
 
@@ -397,6 +579,80 @@ fn fetch(l: lua::State) -> Result<i32> {
     internal_query(l, QueryType::FetchAll)
 }
 
+#[lua_function]
+fn fetch_value(l: lua::State) -> Result<i32> {
+    internal_query(l, QueryType::FetchValue)
+}
+
+// txn:Savepoint(name), the name defaults to one based on the depth
+#[lua_function]
+fn savepoint(l: lua::State) -> Result<i32> {
+    let txn_mutex = Transaction::extract_userdata(l)?;
+    let name = match l.is_none_or_nil(2) {
+        true => format!("sp{}", txn_mutex.blocking_lock().savepoints.len() + 1),
+        false => l.check_string(2)?.into_owned(),
+    };
+
+    let sql = format!("SAVEPOINT {}", escape::identifier(&name));
+    savepoint_query(l, txn_mutex, sql, SavepointAction::Set(name))
+}
+
+// txn:RollbackTo(name), the innermost savepoint if no name is given
+#[lua_function]
+fn rollback_to(l: lua::State) -> Result<i32> {
+    let txn_mutex = Transaction::extract_userdata(l)?;
+    let (idx, name) = find_savepoint(l, &txn_mutex)?;
+
+    let sql = format!("ROLLBACK TO SAVEPOINT {}", escape::identifier(&name));
+    savepoint_query(l, txn_mutex, sql, SavepointAction::RollbackTo(idx))
+}
+
+// txn:Release(name), the innermost savepoint if no name is given, savepoints after it are released too
+#[lua_function]
+fn release(l: lua::State) -> Result<i32> {
+    let txn_mutex = Transaction::extract_userdata(l)?;
+    let (idx, name) = find_savepoint(l, &txn_mutex)?;
+
+    let sql = format!("RELEASE SAVEPOINT {}", escape::identifier(&name));
+    savepoint_query(l, txn_mutex, sql, SavepointAction::Release(idx))
+}
+
+#[lua_function]
+fn depth(l: lua::State) -> Result<i32> {
+    let txn_mutex = Transaction::extract_userdata(l)?;
+    let depth = txn_mutex.blocking_lock().savepoints.len();
+    l.push_number(depth);
+    Ok(1)
+}
+
+fn find_savepoint(l: lua::State, txn_mutex: &Mutex<Transaction>) -> Result<(usize, String)> {
+    let txn = txn_mutex.blocking_lock();
+    if l.is_none_or_nil(2) {
+        return match txn.savepoints.last() {
+            Some(name) => Ok((txn.savepoints.len() - 1, name.clone())),
+            None => bail!("there is no savepoint"),
+        };
+    }
+
+    let name = l.check_string(2)?;
+    match txn.savepoints.iter().position(|n| *n == name) {
+        Some(idx) => Ok((idx, name.into_owned())),
+        None => bail!("there is no savepoint named '{}'", name),
+    }
+}
+
+fn savepoint_query(
+    l: lua::State,
+    txn_mutex: Arc<Mutex<Transaction>>,
+    sql: String,
+    action: SavepointAction,
+) -> Result<i32> {
+    let mut query = Query::new(sql, QueryType::Execute);
+    // nothing to bind, sent as is
+    query.raw = true;
+    run_query(l, txn_mutex, query, Some(action))
+}
+
 fn finalize(l: lua::State, action: Action) -> Result<i32> {
     let traceback = l.get_traceback(l, 1).into_owned();
     let txn_mutex = Transaction::extract_userdata(l)?;
@@ -485,3 +741,81 @@ pub(super) fn get_coroutine(l: lua::State, co_ref: i32) -> lua::State {
     l.pop();
     co
 }
+
+// the refs belong to the lua state that is closing, the next map gets a new one
+pub fn clear_coroutine_pool(l: lua::State) {
+    let pool = COROUTINE_POOL.lock().unwrap().drain();
+    for co_ref in pool {
+        l.dereference(co_ref);
+    }
+}
+
+fn take_coroutine(l: lua::State) -> i32 {
+    if let Some(co_ref) = COROUTINE_POOL.lock().unwrap().take() {
+        return co_ref;
+    }
+
+    l.coroutine_new();
+    l.reference()
+}
+
+fn release_coroutine(l: lua::State, co_ref: i32) {
+    let co = get_coroutine(l, co_ref);
+    if COROUTINE_POOL
+        .lock()
+        .unwrap()
+        .put_back(co_ref, co.coroutine_status())
+    {
+        co.set_top(0); // whatever the function returned
+    } else {
+        l.dereference(co_ref);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gmod::lua::{LUA_ERRRUN, LUA_OK, LUA_YIELD};
+
+    use super::CoroutinePool;
+    use crate::constants::TRANSACTION_COROUTINE_POOL_SIZE;
+
+    // refs stand in for lua registry refs, next_ref counts how many coroutines had to be created
+    #[test]
+    fn sequential_transactions_reuse_one_coroutine() {
+        let mut pool = CoroutinePool::new();
+        let mut next_ref = 0;
+        for _ in 0..100 {
+            let co_ref = pool.take().unwrap_or_else(|| {
+                next_ref += 1;
+                next_ref
+            });
+            assert!(pool.put_back(co_ref, LUA_OK));
+        }
+        assert_eq!(next_ref, 1);
+        assert_eq!(pool.drain(), [1]);
+    }
+
+    #[test]
+    fn pool_stays_bounded() {
+        let mut pool = CoroutinePool::new();
+        let kept = (1..=100)
+            .filter(|&co_ref| pool.put_back(co_ref, LUA_OK))
+            .count();
+        assert_eq!(kept, TRANSACTION_COROUTINE_POOL_SIZE);
+        assert_eq!(pool.drain().len(), TRANSACTION_COROUTINE_POOL_SIZE);
+    }
+
+    #[test]
+    fn errored_or_yielded_coroutines_are_not_reused() {
+        let mut pool = CoroutinePool::new();
+        assert!(!pool.put_back(1, LUA_ERRRUN));
+        assert!(!pool.put_back(2, LUA_YIELD));
+        assert!(pool.take().is_none());
+
+        // a double release must not hand the same coroutine out twice
+        assert!(pool.put_back(3, LUA_OK));
+        assert!(pool.put_back(3, LUA_OK));
+        assert_eq!(pool.take(), Some(3));
+        assert!(pool.take().is_none());
+    }
+}
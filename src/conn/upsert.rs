@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use gmod::*;
+
+use crate::{
+    query::{escape, param::Param, to_param, Query, QueryResult, QueryType},
+    run_async, wait_async, MAX_PLACEHOLDERS,
+};
+
+use super::{internal_query, Conn};
+
+// conn:Upsert(table, key_columns, value_columns, rows, callback_or_options)
+pub(super) fn new(l: lua::State, conn: Arc<Conn>) -> Result<i32> {
+    let traceback = l.get_traceback(l, 1).into_owned();
+
+    let table = l.check_string(2)?.into_owned();
+    let key_columns = check_columns(l, 3)?;
+    let value_columns = check_columns(l, 4)?;
+    if key_columns.is_empty() {
+        bail!("key_columns can't be empty");
+    }
+    if value_columns.is_empty() {
+        bail!("value_columns can't be empty, use INSERT IGNORE if nothing should be updated");
+    }
+    l.check_table(5)?;
+
+    let columns: Vec<String> = key_columns
+        .into_iter()
+        .chain(value_columns.clone())
+        .collect();
+    let rows = read_rows(l, 5, &columns)?;
+
+    let mut query = Query::new(String::new(), QueryType::Execute);
+    query.parse_callback_or_options(l, 6)?;
+    // the rows are always bound
+    query.raw = false;
+
+    let statements = build_statements(&table, &columns, &value_columns, rows);
+
+    if query.sync {
+        conn.check_no_open_cursor()?;
+        let (mut query, res) = wait_async(l, async move {
+            let res = upsert(conn, &mut query, statements).await;
+            (query, res)
+        });
+        return Ok(query.process_result(l, res.map(QueryResult::Count), None));
+    }
+
+    run_async(async move {
+        let res = upsert(conn, &mut query, statements).await;
+        wait_lua_tick(traceback.clone(), move |l| {
+            query.process_result(l, res.map(QueryResult::Count), Some(&traceback));
+        });
+    });
+
+    Ok(0)
+}
+
+fn check_columns(l: lua::State, arg_n: i32) -> Result<Vec<String>> {
+    l.check_table(arg_n)?;
+    let mut columns = Vec::new();
+    for i in 1..=l.len(arg_n) {
+        l.raw_geti(arg_n, i);
+        let column = l.check_string(-1).map(|s| s.into_owned());
+        l.pop();
+        columns.push(column?);
+    }
+    Ok(columns)
+}
+
+// rows are { column = value } tables, missing columns are inserted as NULL
+fn read_rows(l: lua::State, arg_n: i32, columns: &[String]) -> Result<Vec<Vec<Param>>> {
+    let mut rows = Vec::new();
+    for i in 1..=l.len(arg_n) {
+        l.raw_geti(arg_n, i);
+        if l.lua_type(-1) != LUA_TTABLE {
+            l.pop();
+            bail!("row {} is not a table", i);
+        }
+
+        let mut row = Vec::with_capacity(columns.len());
+        for column in columns {
+            l.get_field(-1, &cstring(column));
+            let param = to_param(l, format_args!("{} of row {}", column, i));
+            l.pop();
+            match param {
+                Ok(param) => row.push(param),
+                Err(e) => {
+                    l.pop();
+                    return Err(e);
+                }
+            }
+        }
+        l.pop();
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+// as many rows per statement as the placeholder limit allows
+fn build_statements(
+    table: &str,
+    columns: &[String],
+    value_columns: &[String],
+    rows: Vec<Vec<Param>>,
+) -> Vec<(String, Vec<Param>)> {
+    let column_list = columns
+        .iter()
+        .map(|c| escape::identifier(c))
+        .collect::<Vec<_>>()
+        .join(", ");
+    // VALUES() is deprecated on mysql 8.0.20+ in favor of row aliases, mariadb only has VALUES() though
+    let updates = value_columns
+        .iter()
+        .map(|c| {
+            let c = escape::identifier(c);
+            format!("{} = VALUES({})", c, c)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let row_placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+    let rows_per_statement = (MAX_PLACEHOLDERS as usize / columns.len()).max(1);
+
+    rows.chunks(rows_per_statement)
+        .map(|chunk| {
+            let sql = format!(
+                "INSERT INTO {} ({}) VALUES {} ON DUPLICATE KEY UPDATE {}",
+                escape::qualified(table),
+                column_list,
+                vec![row_placeholders.as_str(); chunk.len()].join(", "),
+                updates
+            );
+            (sql, chunk.concat())
+        })
+        .collect()
+}
+
+// statements run one after another, a failed one stops the rest but the ones before it stay applied
+async fn upsert(
+    conn: Arc<Conn>,
+    query: &mut Query,
+    statements: Vec<(String, Vec<Param>)>,
+) -> Result<u64> {
+    let mut total = 0;
+    for (sql, params) in statements {
+        query.query = sql;
+        query.params = params;
+        match internal_query(conn.clone(), query).await? {
+            QueryResult::Execute(info) => total += info.rows_affected(),
+            _ => return Err(anyhow!("upsert didn't return a query result")),
+        }
+    }
+    Ok(total)
+}
@@ -25,8 +25,47 @@ pub const GLOBAL_TABLE_NAME: &str = {
 };
 pub const GLOBAL_TABLE_NAME_C: LuaCStr = cstr_from_args!(GLOBAL_TABLE_NAME);
 
+// Most placeholders a single prepared statement can have
+pub const MAX_PLACEHOLDERS: u32 = 65_535;
+
+// Default max_allowed_packet of MySQL 8 (64MB), the server can be configured differently
+pub const MAX_ALLOWED_PACKET_DEFAULT: u32 = 64 * 1024 * 1024;
+
+// Delay before the first connect retry, doubled after every failed attempt
+pub const CONNECT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+// The doubled delay stops growing here
+pub const MAX_CONNECT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
 // How many threads to use for the runtime
 pub const DEFAULT_WORKER_THREADS: u16 = 1;
 
-// How long to wait for pending tasks to complete before unloading
+// How many queries can run at the same time across all connections, 0 means no limit
+pub const DEFAULT_MAX_CONCURRENT_QUERIES: u32 = 0;
+
+// How long to let running queries finish on their own before cancelling them when unloading
+pub const QUERIES_CANCEL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+// How long goobie_mysql.OnShutdown hooks get in total, hooks that didn't start by then are skipped
+pub const SHUTDOWN_HOOKS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How long to wait for pending tasks to complete (after cancelling queries) before unloading
 pub const TASKS_WAITING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+
+// Base delay between retries of a query that hit a deadlock, multiplied by the attempt number
+pub const DEADLOCK_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(50);
+
+// Most retries retry_on_deadlock can ask for, the backoff adds up to almost 3 seconds by then
+pub const MAX_DEADLOCK_RETRIES: u32 = 10;
+
+// How deep tables passed to goobie_mysql.JSON can nest, mostly to catch tables that reference themselves
+pub const MAX_JSON_DEPTH: u32 = 64;
+
+// How long conn:Healthcheck waits for the ping and test query before reporting the connection as unhealthy
+pub const HEALTHCHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How many finished transaction coroutines are kept around to be reused by later transactions
+pub const TRANSACTION_COROUTINE_POOL_SIZE: usize = 16;
+
+// How many rows conn:FetchToFile writes between on_progress calls
+pub const EXPORT_PROGRESS_ROWS: u64 = 1000;
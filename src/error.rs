@@ -6,11 +6,75 @@ use crate::cstr_from_args;
 
 const META_NAME: LuaCStr = cstr_from_args!(crate::GLOBAL_TABLE_NAME, "_error");
 
+// errors raised by us (not mysql/sqlx) that lua should be able to check without matching on messages
+#[derive(Debug)]
+pub struct KindError {
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl KindError {
+    pub fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        KindError {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for KindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for KindError {}
+
+// LATEST DETECTED DEADLOCK section of SHOW ENGINE INNODB STATUS, attached as context to a deadlock error
+#[derive(Debug)]
+pub struct DeadlockInfo(pub String);
+
+impl std::fmt::Display for DeadlockInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub fn mysql_error_number(e: &anyhow::Error) -> Option<u16> {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db_e)) => db_e
+            .try_downcast_ref::<MySqlDatabaseError>()
+            .map(|mysql_e| mysql_e.number()),
+        _ => None,
+    }
+}
+
+// the connection is gone, anything that depended on it (like a transaction) is lost too
+pub fn is_connection_lost(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Io(_) | sqlx::Error::WorkerCrashed) => true,
+        // server has gone away, lost connection during query
+        _ => matches!(mysql_error_number(e), Some(2006 | 2013)),
+    }
+}
+
+// the query future was dropped on shutdown, the connection is left in the middle of a result and
+// can't be used for anything else, not even a clean close
+pub fn is_cancelled(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<KindError>(), Some(e) if e.kind == "runtime_shutdown")
+}
+
 // call this function after creating a table
 fn handle_database_error(l: lua::State, db_e: &MySqlDatabaseError) -> String {
     if let Some(sqlstate) = db_e.code() {
         l.push_string(sqlstate);
         l.set_field(-2, c"sqlstate");
+
+        // the class groups related errors, e.g. 23 is any integrity constraint violation
+        if let Some(class) = sqlstate.get(..2) {
+            l.push_string(class);
+            l.set_field(-2, c"sqlstate_class");
+        }
     }
 
     l.push_number(db_e.number());
@@ -19,6 +83,45 @@ fn handle_database_error(l: lua::State, db_e: &MySqlDatabaseError) -> String {
     db_e.message().to_string()
 }
 
+// call this function after creating a table, errors that didn't come from the server
+fn handle_other_sqlx_error(l: lua::State, e: &sqlx::Error) {
+    let kind = match e {
+        sqlx::Error::PoolTimedOut => "pool_timed_out",
+        sqlx::Error::RowNotFound => "row_not_found",
+        sqlx::Error::ColumnNotFound(column) => {
+            l.push_string(column);
+            l.set_field(-2, c"column");
+            "column_not_found"
+        }
+        sqlx::Error::ColumnDecode { index, .. } => {
+            // sqlx gives the column as written in the row access, a name or an index
+            l.push_string(index.trim_matches('"'));
+            l.set_field(-2, c"column");
+            "column_decode"
+        }
+        sqlx::Error::Io(io_e) => {
+            l.push_string(&io_kind_name(io_e.kind()));
+            l.set_field(-2, c"io_kind");
+            "io"
+        }
+        _ => return,
+    };
+    l.push_string(kind);
+    l.set_field(-2, c"kind");
+}
+
+// ConnectionReset -> connection_reset
+fn io_kind_name(kind: std::io::ErrorKind) -> String {
+    let mut name = String::new();
+    for (idx, c) in format!("{:?}", kind).chars().enumerate() {
+        if c.is_ascii_uppercase() && idx > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
 // call this function after creating a table
 fn handle_sqlx_error_internal(l: lua::State, e: &sqlx::Error) -> String {
     let msg = match e {
@@ -26,7 +129,10 @@ fn handle_sqlx_error_internal(l: lua::State, e: &sqlx::Error) -> String {
             Some(mysql_e) => handle_database_error(l, mysql_e),
             _ => e.to_string(),
         },
-        _ => e.to_string(),
+        _ => {
+            handle_other_sqlx_error(l, e);
+            e.to_string()
+        }
     };
 
     l.push_string(&msg);
@@ -36,10 +142,20 @@ fn handle_sqlx_error_internal(l: lua::State, e: &sqlx::Error) -> String {
 }
 
 pub fn handle_error(l: lua::State, e: anyhow::Error) -> String {
-    l.create_table(0, 3);
+    l.create_table(0, 4);
     l.get_metatable_name(META_NAME);
     unsafe { l.set_metatable(-2) };
 
+    if let Some(kind_e) = e.downcast_ref::<KindError>() {
+        l.push_string(kind_e.kind);
+        l.set_field(-2, c"kind");
+    }
+
+    if let Some(info) = e.downcast_ref::<DeadlockInfo>() {
+        l.push_string(&info.0);
+        l.set_field(-2, c"deadlock_info");
+    }
+
     let msg = match e.downcast_ref::<sqlx::Error>() {
         Some(sqlx_e) => handle_sqlx_error_internal(l, sqlx_e),
         _ => e.to_string(),
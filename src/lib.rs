@@ -5,6 +5,9 @@ mod constants;
 mod error;
 mod query;
 mod runtime;
+mod shutdown;
+#[cfg(test)]
+mod test_db;
 
 pub use constants::*;
 pub use runtime::{run_async, wait_async};
@@ -13,6 +16,10 @@ pub static mut GMOD_CLOSED: bool = false;
 
 const METHODS: &[LuaReg] = lua_regs![
     "Poll" => poll,
+    "EscapeString" => escape_string,
+    "JSON" => query::json::new_param,
+    "Int" => query::int::new_param,
+    "OnShutdown" => shutdown::on_shutdown,
 ];
 
 #[inline]
@@ -31,19 +38,38 @@ fn gmod13_open(l: lua::State) -> i32 {
     {
         l.push_string(crate::VERSION);
         l.set_field(-2, c"VERSION");
+
+        l.push_number(MAX_PLACEHOLDERS);
+        l.set_field(-2, c"MAX_PLACEHOLDERS");
+
+        l.push_number(MAX_ALLOWED_PACKET_DEFAULT);
+        l.set_field(-2, c"MAX_ALLOWED_PACKET_DEFAULT");
+
+        #[cfg(feature = "runtime-metrics")]
+        {
+            l.push_function(runtime_metrics);
+            l.set_field(-2, c"RuntimeMetrics");
+        }
     }
     l.pop();
 
-    runtime::load(get_max_worker_threads(l));
+    runtime::load(get_max_worker_threads(l), get_max_concurrent_queries(l));
 
     conn::on_gmod_open::init(l);
     error::init(l);
+    query::json::init(l);
+    query::int::init(l);
+    query::result::init(l);
 
     0
 }
 
 #[gmod13_close]
 fn gmod13_close(l: lua::State) -> i32 {
+    // before anything shuts down, hooks are there to get their last writes in
+    shutdown::run_hooks(l);
+    conn::on_gmod_open::on_gmod_close(l);
+
     unsafe {
         GMOD_CLOSED = true;
     }
@@ -60,14 +86,68 @@ fn poll(l: lua::State) -> i32 {
     0
 }
 
+#[lua_function]
+fn escape_string(l: lua::State) -> anyhow::Result<i32> {
+    l.check_string(1)?;
+    let s = l.get_binary_string(1).unwrap_or_default();
+    l.push_binary_string(&query::escape::string(s, "utf8mb4", false));
+    Ok(1)
+}
+
+#[cfg(feature = "runtime-metrics")]
+#[lua_function]
+fn runtime_metrics(l: lua::State) -> anyhow::Result<i32> {
+    runtime::ensure_running()?;
+    let metrics = runtime::metrics();
+    l.create_table(0, 6);
+    {
+        l.push_number(metrics.workers);
+        l.set_field(-2, c"workers");
+
+        l.push_number(metrics.busy_workers);
+        l.set_field(-2, c"busy_workers");
+
+        l.push_number(metrics.workers - metrics.busy_workers);
+        l.set_field(-2, c"idle_workers");
+
+        l.push_number(metrics.alive_tasks);
+        l.set_field(-2, c"alive_tasks");
+
+        l.push_number(metrics.pending_tasks);
+        l.set_field(-2, c"pending_tasks");
+
+        l.push_number(metrics.global_queue_depth);
+        l.set_field(-2, c"global_queue_depth");
+    }
+    Ok(1)
+}
+
 fn get_max_worker_threads(l: lua::State) -> u16 {
-    let mut max_worker_threads = DEFAULT_WORKER_THREADS;
+    get_convar_int(
+        l,
+        "GOOBIE_MYSQL_WORKER_THREADS",
+        DEFAULT_WORKER_THREADS as u32,
+        "Number of worker threads for the mysql connection pool",
+    ) as u16
+}
+
+fn get_max_concurrent_queries(l: lua::State) -> u32 {
+    get_convar_int(
+        l,
+        "GOOBIE_MYSQL_MAX_CONCURRENT_QUERIES",
+        DEFAULT_MAX_CONCURRENT_QUERIES,
+        "Max number of queries running at the same time across all connections, 0 for no limit",
+    )
+}
+
+fn get_convar_int(l: lua::State, name: &str, default: u32, help: &str) -> u32 {
+    let mut value = default;
 
     l.get_global(c"CreateConVar");
     if l.is_function(-1) {
         {
-            l.push_string("GOOBIE_MYSQL_WORKER_THREADS");
-            l.push_number(DEFAULT_WORKER_THREADS);
+            l.push_string(name);
+            l.push_number(default);
             l.create_table(2, 0);
             {
                 l.get_global(c"FCVAR_ARCHIVE");
@@ -76,7 +156,7 @@ fn get_max_worker_threads(l: lua::State) -> u16 {
                 l.get_global(c"FCVAR_PROTECTED");
                 l.raw_seti(-2, 2);
             }
-            l.push_string("Number of worker threads for the mysql connection pool");
+            l.push_string(help);
         }
 
         if l.pcall(4, 1, 0).is_ok() {
@@ -85,7 +165,7 @@ fn get_max_worker_threads(l: lua::State) -> u16 {
                 l.push_value(-2);
             }
             if l.pcall(1, 1, 0).is_ok() {
-                max_worker_threads = l.to_number(-1) as u16;
+                value = l.to_number(-1) as u32;
                 l.pop(); // pop the number
             } else {
                 l.pop(); // pop the error
@@ -98,7 +178,7 @@ fn get_max_worker_threads(l: lua::State) -> u16 {
         l.pop(); // pop the nil or whatever non function value
     }
 
-    max_worker_threads
+    value
 }
 
 #[macro_export]
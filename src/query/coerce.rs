@@ -0,0 +1,102 @@
+use anyhow::{bail, Result};
+use gmod::*;
+
+use super::json;
+
+// overrides how a column gets converted to lua, regardless of its sql type
+#[derive(Debug, Clone, Copy)]
+pub enum Coerce {
+    Json,
+    Bool,
+    Number,
+    String,
+}
+
+impl Coerce {
+    pub fn from_name(name: &str) -> Result<Self> {
+        Ok(match name {
+            "json" => Coerce::Json,
+            "bool" => Coerce::Bool,
+            "number" => Coerce::Number,
+            "string" => Coerce::String,
+            _ => bail!("unknown coerce type: {}", name),
+        })
+    }
+
+    // converts the value at the top of the stack in place, nil is left as is
+    pub fn apply(self, l: lua::State, column_name: &str, json_ordered: bool) -> Result<()> {
+        let value_type = l.lua_type(-1);
+        if value_type == LUA_TNIL || is_already(self, value_type) {
+            return Ok(());
+        }
+
+        match (self, value_type) {
+            (Coerce::Json, LUA_TSTRING) => {
+                let value: serde_json::Value =
+                    serde_json::from_slice(l.get_binary_string(-1).unwrap_or_default())?;
+                l.pop();
+                json::push_to_lua(l, &value, json_ordered);
+            }
+            (Coerce::Bool, LUA_TNUMBER) => {
+                let b = l.to_number(-1) != 0.0;
+                l.pop();
+                l.push_boolean(b);
+            }
+            (Coerce::Bool, LUA_TSTRING) => {
+                let b = match parse_bool(l.get_binary_string(-1).unwrap_or_default()) {
+                    Some(b) => b,
+                    None => bail!("can't coerce column '{}' to a bool", column_name),
+                };
+                l.pop();
+                l.push_boolean(b);
+            }
+            (Coerce::Number, LUA_TBOOLEAN) => {
+                let n = if l.get_boolean(-1) { 1 } else { 0 };
+                l.pop();
+                l.push_number(n);
+            }
+            (Coerce::Number, LUA_TSTRING) => {
+                let s = l.get_binary_string(-1).unwrap_or_default();
+                let n = match std::str::from_utf8(s)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<f64>().ok())
+                {
+                    Some(n) => n,
+                    None => bail!("can't coerce column '{}' to a number", column_name),
+                };
+                l.pop();
+                l.push_number(n);
+            }
+            (Coerce::String, LUA_TNUMBER) => {
+                let n = l.to_number(-1);
+                l.pop();
+                l.push_string(&n.to_string());
+            }
+            (Coerce::String, LUA_TBOOLEAN) => {
+                let b = l.get_boolean(-1);
+                l.pop();
+                l.push_string(if b { "1" } else { "0" });
+            }
+            _ => bail!("can't coerce column '{}' to {:?}", column_name, self),
+        }
+
+        Ok(())
+    }
+}
+
+fn is_already(coerce: Coerce, value_type: i32) -> bool {
+    matches!(
+        (coerce, value_type),
+        (Coerce::Bool, LUA_TBOOLEAN)
+            | (Coerce::Number, LUA_TNUMBER)
+            | (Coerce::String, LUA_TSTRING)
+    )
+}
+
+pub fn parse_bool(s: &[u8]) -> Option<bool> {
+    match s {
+        b"1" | b"true" | b"TRUE" | b"Y" | b"y" => Some(true),
+        b"0" | b"false" | b"FALSE" | b"N" | b"n" | b"" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,189 @@
+// identifiers can't be bound as parameters, so they have to be quoted by hand
+// backticks inside the name are escaped by doubling them
+pub fn identifier(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len() + 2);
+    escaped.push('`');
+    for c in name.chars() {
+        if c == '`' {
+            escaped.push('`');
+        }
+        escaped.push(c);
+    }
+    escaped.push('`');
+    escaped
+}
+
+// db.table or table.column, each part is quoted on its own, otherwise `db.table` would be taken as a
+// single table name with a dot in it
+pub fn qualified(name: &str) -> String {
+    name.split('.')
+        .map(identifier)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// the name a qualified column shows up as in a result
+pub fn unqualified(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+// mirrors mysql_real_escape_string, valid multibyte characters are copied as is so a trailing byte
+// that happens to be a backslash or a quote (e.g. 0xbf5c in gbk) can't be used to break out of the string
+// with NO_BACKSLASH_ESCAPES a backslash is just a backslash to the server, quotes are doubled instead
+pub fn string(input: &[u8], charset: &str, no_backslash_escapes: bool) -> Vec<u8> {
+    let charset = charset.to_ascii_lowercase();
+    let mut escaped = Vec::with_capacity(input.len() * 2);
+    let mut i = 0;
+    while i < input.len() {
+        let char_len = multibyte_len(&charset, &input[i..]);
+        if char_len > 1 {
+            escaped.extend_from_slice(&input[i..i + char_len]);
+            i += char_len;
+            continue;
+        }
+
+        if no_backslash_escapes {
+            if input[i] == b'\'' {
+                escaped.push(b'\'');
+            }
+            escaped.push(input[i]);
+            i += 1;
+            continue;
+        }
+
+        // a lead byte that isn't followed by a valid character is escaped itself, otherwise
+        // 0xbf followed by the backslash escaping a quote would become the valid character 0xbf5c
+        if is_lead_byte(&charset, input[i]) {
+            escaped.push(b'\\');
+            escaped.push(input[i]);
+            i += 1;
+            continue;
+        }
+
+        match input[i] {
+            0 => escaped.extend_from_slice(b"\\0"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\'' => escaped.extend_from_slice(b"\\'"),
+            b'"' => escaped.extend_from_slice(b"\\\""),
+            0x1a => escaped.extend_from_slice(b"\\Z"),
+            b => escaped.push(b),
+        }
+        i += 1;
+    }
+    escaped
+}
+
+fn is_lead_byte(charset: &str, b: u8) -> bool {
+    match charset {
+        "gbk" | "gb18030" => (0x81..=0xfe).contains(&b),
+        "big5" => (0xa1..=0xf9).contains(&b),
+        "sjis" | "cp932" => (0x81..=0x9f).contains(&b) || (0xe0..=0xfc).contains(&b),
+        _ => false,
+    }
+}
+
+// length of the character at the start of bytes if it's a valid multibyte one, 1 otherwise
+// utf8 doesn't need this, none of its continuation bytes can be ascii
+fn multibyte_len(charset: &str, bytes: &[u8]) -> usize {
+    if !is_lead_byte(charset, bytes[0]) {
+        return 1;
+    }
+
+    let trail = |idx: usize| bytes.get(idx).copied();
+    let valid = match charset {
+        "gbk" => matches!(trail(1), Some(0x40..=0x7e | 0x80..=0xfe)),
+        "big5" => matches!(trail(1), Some(0x40..=0x7e | 0xa1..=0xfe)),
+        "sjis" | "cp932" => matches!(trail(1), Some(0x40..=0x7e | 0x80..=0xfc)),
+        "gb18030" => {
+            if matches!(
+                (trail(1), trail(2), trail(3)),
+                (Some(0x30..=0x39), Some(0x81..=0xfe), Some(0x30..=0x39))
+            ) {
+                return 4;
+            }
+            matches!(trail(1), Some(0x40..=0x7e | 0x80..=0xfe))
+        }
+        _ => false,
+    };
+
+    if valid {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(
+            string(b"it's \"a\"\n\\\0\x1a", "utf8mb4", false),
+            b"it\\'s \\\"a\\\"\\n\\\\\\0\\Z"
+        );
+    }
+
+    #[test]
+    fn gbk_lead_byte_before_quote() {
+        // 0xbf27 isn't a gbk character, escaping only the quote would give 0xbf5c (a valid one) and a bare quote
+        assert_eq!(
+            string(b"\xbf' OR 1=1 -- ", "gbk", false),
+            b"\\\xbf\\' OR 1=1 -- "
+        );
+        // 0xbf5c is a full character here, so only the quote after it needs escaping
+        assert_eq!(string(b"\xbf\\' OR 1=1", "gbk", false), b"\xbf\\\\' OR 1=1");
+    }
+
+    #[test]
+    fn gbk_valid_character_kept() {
+        // 0xbf5c is a valid gbk character, its trailing backslash must not be escaped
+        assert_eq!(string(b"\xbf\\", "gbk", false), b"\xbf\\");
+        assert_eq!(string(b"\xd6\xd0'", "gbk", false), b"\xd6\xd0\\'");
+    }
+
+    #[test]
+    fn other_multibyte_charsets() {
+        assert_eq!(string(b"\xa1'", "big5", false), b"\\\xa1\\'");
+        assert_eq!(string(b"\x81'", "sjis", false), b"\\\x81\\'");
+        assert_eq!(string(b"\x95\\", "cp932", false), b"\x95\\");
+        assert_eq!(
+            string(b"\x81\x30\x81\x30'", "gb18030", false),
+            b"\x81\x30\x81\x30\\'"
+        );
+        assert_eq!(string(b"\x81\x30'", "gb18030", false), b"\\\x81\x30\\'");
+    }
+
+    #[test]
+    fn incomplete_character_at_the_end() {
+        assert_eq!(string(b"'\xbf", "gbk", false), b"\\'\\\xbf");
+    }
+
+    #[test]
+    fn utf8_bytes_are_not_lead_bytes() {
+        assert_eq!(string("é'".as_bytes(), "utf8mb4", false), "é\\'".as_bytes());
+    }
+
+    #[test]
+    fn no_backslash_escapes_doubles_quotes() {
+        assert_eq!(string(b"it's a \\", "utf8mb4", true), b"it''s a \\");
+        assert_eq!(string(b"\xbf'", "gbk", true), b"\xbf''");
+    }
+
+    #[test]
+    fn identifier_doubles_backticks() {
+        assert_eq!(identifier("a`b"), "`a``b`");
+    }
+
+    #[test]
+    fn qualified_quotes_each_part() {
+        assert_eq!(qualified("db.table"), "`db`.`table`");
+        assert_eq!(qualified("table"), "`table`");
+        assert_eq!(qualified("t.i`d"), "`t`.`i``d`");
+        assert_eq!(unqualified("t.id"), "id");
+        assert_eq!(unqualified("id"), "id");
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{bail, Result};
+use sqlx::{
+    mysql::{MySqlQueryResult, MySqlRow},
+    Executor as _, MySqlConnection, Row as _,
+};
+
+use super::escape;
+
+// mysql has no RETURNING, so the inserted row is selected again using the auto increment column
+pub async fn fetch(
+    conn: &mut MySqlConnection,
+    query: &str,
+    info: &MySqlQueryResult,
+) -> Result<Option<MySqlRow>> {
+    let table = match insert_table(query) {
+        Some(table) => table,
+        None => bail!("fetch_inserted only works with INSERT queries"),
+    };
+
+    // nothing was inserted, e.g. INSERT IGNORE that hit a duplicate
+    if info.last_insert_id() == 0 {
+        return Ok(None);
+    }
+
+    // the table is passed as it was written in the query, so `db`.`table` works too
+    let show_columns = format!(
+        "SHOW COLUMNS FROM {} WHERE Extra LIKE '%auto_increment%'",
+        table
+    );
+    let columns = conn.fetch_all(show_columns.as_str()).await?;
+    let key: String = match columns.first() {
+        Some(row) => row.try_get_unchecked(0)?,
+        None => bail!(
+            "fetch_inserted needs {} to have an AUTO_INCREMENT column",
+            table
+        ),
+    };
+
+    let select = format!(
+        "SELECT * FROM {} WHERE {} = ?",
+        table,
+        escape::identifier(&key)
+    );
+    let row = conn
+        .fetch_optional(sqlx::query(&select).bind(info.last_insert_id()))
+        .await?;
+    Ok(row)
+}
+
+// INSERT [LOW_PRIORITY | DELAYED | HIGH_PRIORITY] [IGNORE] [INTO] table ...
+fn insert_table(query: &str) -> Option<&str> {
+    let mut rest = query.trim_start();
+    let mut saw_insert = false;
+    loop {
+        let word_len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let word = &rest[..word_len];
+        if !saw_insert {
+            if !word.eq_ignore_ascii_case("insert") {
+                return None;
+            }
+            saw_insert = true;
+        } else if !["low_priority", "delayed", "high_priority", "ignore", "into"]
+            .iter()
+            .any(|keyword| word.eq_ignore_ascii_case(keyword))
+        {
+            break;
+        }
+        rest = rest[word_len..].trim_start();
+    }
+
+    // table name ends at the column list, VALUES/SET/SELECT or the end of the query
+    let mut in_backticks = false;
+    let end = rest
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '`' {
+                in_backticks = !in_backticks;
+            }
+            !in_backticks && (c.is_whitespace() || c == '(' || c == ';')
+        })
+        .map_or(rest.len(), |(idx, _)| idx);
+
+    let table = &rest[..end];
+    if table.is_empty() || in_backticks {
+        return None;
+    }
+    Some(table)
+}
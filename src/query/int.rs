@@ -0,0 +1,61 @@
+use anyhow::{bail, Result};
+use gmod::*;
+
+use crate::cstr_from_args;
+
+use super::param::Param;
+
+const META_NAME: LuaCStr = cstr_from_args!(crate::GLOBAL_TABLE_NAME, "_int");
+
+// parsed when created, so a bad value errors where it was written instead of when the query runs
+#[derive(Debug, Clone, Copy)]
+enum Int {
+    Signed(i64),
+    Unsigned(u64),
+}
+
+pub fn init(l: lua::State) {
+    l.new_metatable(META_NAME);
+    {
+        l.push_function(__tostring);
+        l.set_field(-2, c"__tostring");
+    }
+    l.pop();
+}
+
+// goobie_mysql.Int(str), lua numbers lose precision past 2^53 so big ids like SteamID64s are passed as strings
+#[lua_function]
+pub fn new_param(l: lua::State) -> Result<i32> {
+    let s = l.check_string(1)?;
+    let int = if let Ok(n) = s.parse::<i64>() {
+        Int::Signed(n)
+    } else if let Ok(n) = s.parse::<u64>() {
+        Int::Unsigned(n)
+    } else {
+        bail!("'{}' is not an integer that fits in 64 bits", s);
+    };
+    l.new_userdata(int, Some(META_NAME));
+    Ok(1)
+}
+
+#[lua_function]
+fn __tostring(l: lua::State) -> Result<i32> {
+    let s = match *l.get_userdata::<Int>(1, Some(META_NAME))? {
+        Int::Signed(n) => n.to_string(),
+        Int::Unsigned(n) => n.to_string(),
+    };
+    l.push_string(&s);
+    Ok(1)
+}
+
+pub fn is_param(l: lua::State, idx: i32) -> bool {
+    l.get_userdata::<Int>(idx, Some(META_NAME))
+        .is_ok()
+}
+
+pub fn to_param(l: lua::State, idx: i32) -> Result<Param> {
+    Ok(match *l.get_userdata::<Int>(idx, Some(META_NAME))? {
+        Int::Signed(n) => Param::BigInt(n),
+        Int::Unsigned(n) => Param::BigUnsigned(n),
+    })
+}
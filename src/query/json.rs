@@ -0,0 +1,192 @@
+use anyhow::{bail, Result};
+use gmod::*;
+use serde_json::{Map, Number, Value};
+
+use crate::{cstr_from_args, MAX_JSON_DEPTH};
+
+const META_NAME: LuaCStr = cstr_from_args!(crate::GLOBAL_TABLE_NAME, "_json");
+
+// with `ordered` objects become arrays of {key, value} pairs in the same order as the source
+pub fn push_to_lua(l: lua::State, value: &Value, ordered: bool) {
+    match value {
+        Value::Null => l.push_nil(),
+        Value::Bool(b) => l.push_boolean(*b),
+        Value::Number(n) => l.push_number(n.as_f64().unwrap_or_default()),
+        Value::String(s) => l.push_string(s),
+        Value::Array(values) => {
+            l.create_table(values.len() as i32, 0);
+            for (idx, value) in values.iter().enumerate() {
+                push_to_lua(l, value, ordered);
+                l.raw_seti(-2, idx as i32 + 1);
+            }
+        }
+        Value::Object(map) if ordered => {
+            l.create_table(map.len() as i32, 0);
+            for (idx, (key, value)) in map.iter().enumerate() {
+                l.create_table(2, 0);
+                l.push_string(key);
+                l.raw_seti(-2, 1);
+                push_to_lua(l, value, ordered);
+                l.raw_seti(-2, 2);
+                l.raw_seti(-2, idx as i32 + 1);
+            }
+        }
+        Value::Object(map) => {
+            l.create_table(0, map.len() as i32);
+            for (key, value) in map {
+                l.push_string(key);
+                push_to_lua(l, value, ordered);
+                l.raw_set(-3);
+            }
+        }
+    }
+}
+
+pub fn init(l: lua::State) {
+    l.new_metatable(META_NAME);
+    {
+        l.push_function(__gc);
+        l.set_field(-2, c"__gc");
+    }
+    l.pop();
+}
+
+// goobie_mysql.JSON(tbl), marks a table to be bound as a JSON string param
+#[lua_function]
+pub fn new_param(l: lua::State) -> Result<i32> {
+    l.check_table(1)?;
+    l.push_value(1);
+    let table_ref = l.reference();
+    l.new_userdata(table_ref, Some(META_NAME));
+    Ok(1)
+}
+
+#[lua_function]
+fn __gc(l: lua::State) -> Result<i32> {
+    let table_ref = *l.get_userdata::<i32>(1, Some(META_NAME))?;
+    l.dereference(table_ref);
+    Ok(0)
+}
+
+pub fn is_param(l: lua::State, idx: i32) -> bool {
+    l.get_userdata::<i32>(idx, Some(META_NAME))
+        .is_ok()
+}
+
+pub fn param_to_string(l: lua::State, idx: i32) -> Result<String> {
+    let table_ref = *l.get_userdata::<i32>(idx, Some(META_NAME))?;
+    l.from_reference(table_ref);
+    let value = from_lua(l, 0);
+    l.pop();
+    Ok(serde_json::to_string(&value?)?)
+}
+
+enum Key {
+    Index(usize),
+    Name(String),
+}
+
+// converts the value at the top of the stack, tables with only positive integer keys become arrays
+// and any holes in them become nulls
+fn from_lua(l: lua::State, depth: u32) -> Result<Value> {
+    let value = match l.lua_type(-1) {
+        LUA_TNIL => Value::Null,
+        LUA_TBOOLEAN => Value::Bool(l.get_boolean(-1)),
+        LUA_TNUMBER => {
+            let n = l.to_number(-1);
+            if n.fract() == 0.0 && n.abs() < (1u64 << 53) as f64 {
+                Value::from(n as i64)
+            } else {
+                match Number::from_f64(n) {
+                    Some(n) => Value::Number(n),
+                    None => bail!("can't convert {} to json", n),
+                }
+            }
+        }
+        LUA_TSTRING => Value::String(l.get_string_unchecked(-1).into_owned()),
+        LUA_TTABLE => {
+            if depth >= MAX_JSON_DEPTH {
+                bail!("table is nested too deep to convert to json (does it reference itself?)");
+            }
+
+            let mut entries = Vec::new();
+            l.push_nil();
+            while l.next(-2) != 0 {
+                let key = match l.lua_type(-2) {
+                    LUA_TNUMBER => {
+                        let n = l.to_number(-2);
+                        if n >= 1.0 && n.fract() == 0.0 {
+                            Key::Index(n as usize)
+                        } else {
+                            Key::Name(n.to_string())
+                        }
+                    }
+                    LUA_TSTRING => Key::Name(l.get_string_unchecked(-2).into_owned()),
+                    _ => bail!("unsupported json key type: {}", l.lua_type_name(-2)),
+                };
+                let value = from_lua(l, depth + 1)?;
+                entries.push((key, value));
+                l.pop();
+            }
+
+            let max_index = entries
+                .iter()
+                .map(|(key, _)| match key {
+                    Key::Index(idx) => *idx,
+                    Key::Name(_) => 0,
+                })
+                .max()
+                .unwrap_or(0);
+            let all_indexes = entries
+                .iter()
+                .all(|(key, _)| matches!(key, Key::Index(_)));
+
+            if all_indexes && is_dense(max_index, entries.len()) {
+                let mut array = vec![Value::Null; max_index];
+                for (key, value) in entries {
+                    if let Key::Index(idx) = key {
+                        array[idx - 1] = value;
+                    }
+                }
+                Value::Array(array)
+            } else {
+                let mut map = Map::with_capacity(entries.len());
+                for (key, value) in entries {
+                    let key = match key {
+                        Key::Index(idx) => idx.to_string(),
+                        Key::Name(name) => name,
+                    };
+                    map.insert(key, value);
+                }
+                Value::Object(map)
+            }
+        }
+        _ => bail!("unsupported type for json: {}", l.lua_type_name(-1)),
+    };
+
+    Ok(value)
+}
+
+// sparse tables like {[1e9] = true} would allocate an array of that many nulls, encode those as objects
+fn is_dense(max_index: usize, count: usize) -> bool {
+    max_index <= count.saturating_mul(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_dense;
+
+    #[test]
+    fn dense_tables_are_arrays() {
+        assert!(is_dense(0, 0));
+        assert!(is_dense(3, 3));
+        assert!(is_dense(4, 2));
+    }
+
+    #[test]
+    fn sparse_tables_are_objects() {
+        assert!(!is_dense(5, 2));
+        assert!(!is_dense(1_000_000_000, 1));
+        assert!(!is_dense(usize::MAX, 1));
+    }
+}
@@ -1,17 +1,44 @@
+use std::collections::{HashMap, HashSet};
+
 use anyhow::{bail, Result};
+use futures_util::TryStreamExt as _;
 use gmod::*;
-use sqlx::{Executor as _, MySqlConnection};
+use sqlx::{
+    mysql::{MySqlArguments, MySqlQueryResult, MySqlRow},
+    query::Query as SqlxQuery,
+    Column as _, Either, Executor as _, MySql, MySqlConnection, Row as _,
+};
 
+pub mod coerce;
+pub mod escape;
+mod inserted;
+pub mod int;
+pub mod json;
 pub mod param;
+pub mod placeholder;
 pub mod process;
 pub mod result;
+pub mod script;
+mod top_n;
+pub mod value;
 
 pub use result::{QueryResult, QueryType};
 
+use coerce::Coerce;
 use param::Param;
-use process::{process_info, process_row, process_rows};
+use placeholder::{Placeholder, Style as PlaceholderStyle};
+use process::{
+    estimate_row_size, process_info, process_row, process_row_values, process_rows, process_value,
+    push_named_value, push_row_to_lua, ColumnCase, TimeUnit,
+};
+use top_n::TopN;
 
-use crate::error::handle_error;
+use crate::{
+    error::{handle_error, mysql_error_number, KindError},
+    print_goobie,
+    runtime::{acquire_query_permit, shutdown_signal},
+    DEADLOCK_RETRY_BACKOFF, MAX_DEADLOCK_RETRIES, MAX_PLACEHOLDERS,
+};
 
 pub type Params = Vec<Param>;
 
@@ -23,6 +50,59 @@ pub struct Query {
     pub callback: i32,
     pub sync: bool,
     pub raw: bool,
+    // prepared for this run only and closed right after, instead of kept in the statement cache
+    pub no_cache: bool,
+    pub with_found: bool,
+    pub empty_as_table: bool,
+    pub max_result_bytes: Option<usize>,
+    // from the connection's debug_sql option
+    pub debug_sql: bool,
+    pub retry_on_deadlock: u32,
+    pub retry_after_reconnect: bool,
+    pub capture_deadlock_info: bool,
+    pub shared_columns: bool,
+    pub columnar: bool,
+    pub with_index: bool,
+    pub key_by: Option<String>,
+    pub key_by_unique: bool,
+    pub distinct_by: Option<String>,
+    pub top_n: Option<TopN>,
+    pub default: i32,
+    pub coerce: HashMap<String, Coerce>,
+    // column => its ENUM labels in definition order, the server only sends the label
+    pub enum_as_index: HashMap<String, Vec<Vec<u8>>>,
+    pub json_ordered: bool,
+    pub parse_numeric_strings: bool,
+    pub blob_base64: bool,
+    pub vector_as_floats: bool,
+    pub fields: Option<Vec<String>>,
+    pub column_case: ColumnCase,
+    pub case_insensitive_keys: bool,
+    pub tagged: bool,
+    // rows are sent to lua as a single msgpack encoded string
+    pub msgpack: bool,
+    pub time_unit: Option<TimeUnit>,
+    pub require_rows: bool,
+    // mysql error numbers reported as success, e.g. 1091 for dropping an index that isn't there
+    pub ignore_errors: Vec<u16>,
+    pub charset: Option<String>,
+    // set while the session is switched to `charset`, still set afterwards if putting the old one back
+    // failed or the query was dropped halfway, whoever owns the connection has to run it
+    charset_restore: Option<String>,
+    // database a UseDatabase query switches to, the connection tracks it once the query went through
+    pub use_database: Option<String>,
+    pub fetch_inserted: bool,
+    // COUNT(*) query and its params ran after the rows, used by FetchPage
+    pub total_query: Option<(String, Params)>,
+    // key column and page size, the last key is returned after the rows, used by FetchKeyset
+    pub keyset: Option<(String, usize)>,
+    pub background: bool,
+    // jumps ahead of normal queries waiting for the connection
+    pub high_priority: bool,
+    // queries ran back to back on the same connection, used by FetchPipeline
+    pub pipeline: Vec<Query>,
+    // rows of (name, value) pushed as a single name => value table, used by ServerStatus
+    pub key_value: bool,
 }
 
 impl Query {
@@ -32,6 +112,46 @@ impl Query {
             r#type,
             sync: true,
             raw: false,
+            no_cache: false,
+            with_found: false,
+            empty_as_table: false,
+            max_result_bytes: None,
+            debug_sql: false,
+            retry_on_deadlock: 0,
+            retry_after_reconnect: false,
+            capture_deadlock_info: false,
+            shared_columns: false,
+            columnar: false,
+            with_index: false,
+            key_by: None,
+            key_by_unique: false,
+            distinct_by: None,
+            top_n: None,
+            default: LUA_NOREF,
+            coerce: HashMap::new(),
+            enum_as_index: HashMap::new(),
+            json_ordered: false,
+            parse_numeric_strings: false,
+            blob_base64: false,
+            vector_as_floats: false,
+            fields: None,
+            column_case: ColumnCase::Original,
+            case_insensitive_keys: false,
+            tagged: false,
+            msgpack: false,
+            time_unit: None,
+            require_rows: false,
+            ignore_errors: Vec::new(),
+            charset: None,
+            charset_restore: None,
+            use_database: None,
+            fetch_inserted: false,
+            total_query: None,
+            keyset: None,
+            background: false,
+            high_priority: false,
+            pipeline: Vec::new(),
+            key_value: false,
             params: Vec::new(),
             callback: LUA_NOREF,
         }
@@ -47,11 +167,74 @@ impl Query {
             return Ok(());
         }
 
+        if l.get_field_type_or_nil(arg_n, c"raw", LUA_TBOOLEAN)? {
+            self.raw = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"no_cache", LUA_TBOOLEAN)? {
+            self.no_cache = l.get_boolean(-1);
+            l.pop();
+        }
+
+        let mut placeholder = PlaceholderStyle::Question;
+        if l.get_field_type_or_nil(arg_n, c"placeholder", LUA_TSTRING)? {
+            placeholder = PlaceholderStyle::from_name(&l.get_string_unchecked(-1))?;
+            l.pop();
+        }
+
+        let mut named_params = HashMap::new();
         if l.get_field_type_or_nil(arg_n, c"params", LUA_TTABLE)? {
-            self.bind_params(l)?
+            if placeholder == PlaceholderStyle::Named {
+                named_params = bind_named_params(l)?;
+            } else {
+                self.bind_params(l)?
+            }
+        }
+
+        // raw queries never bind, passing params with them is always a mistake
+        if self.raw && (!self.params.is_empty() || !named_params.is_empty()) {
+            bail!("params can't be used with raw queries, raw queries are sent as is");
+        }
+
+        if placeholder != PlaceholderStyle::Question {
+            self.apply_placeholders(placeholder, named_params)?;
         }
 
         if parse_fns {
+            // inside a transaction a deadlock rolls back the whole transaction, retrying one query wouldn't make sense
+            if l.get_field_type_or_nil(arg_n, c"retry_on_deadlock", LUA_TNUMBER)? {
+                let retries = l.to_number(-1);
+                l.pop();
+                self.retry_on_deadlock = deadlock_retries(retries)?;
+            }
+
+            // the transaction is gone with the connection, it can't be picked up again on a new one
+            if l.get_field_type_or_nil(arg_n, c"retry_after_reconnect", LUA_TBOOLEAN)? {
+                self.retry_after_reconnect = l.get_boolean(-1);
+                l.pop();
+            }
+
+            if l.get_field_type_or_nil(arg_n, c"capture_deadlock_info", LUA_TBOOLEAN)? {
+                self.capture_deadlock_info = l.get_boolean(-1);
+                l.pop();
+            }
+
+            // a transaction is bound to its own connection
+            if l.get_field_type_or_nil(arg_n, c"background", LUA_TBOOLEAN)? {
+                self.background = l.get_boolean(-1);
+                l.pop();
+            }
+
+            if l.get_field_type_or_nil(arg_n, c"priority", LUA_TSTRING)? {
+                self.high_priority = match l.get_string_unchecked(-1).as_ref() {
+                    "normal" => false,
+                    "high" => true,
+                    name => bail!("unknown priority '{}', expected normal or high", name),
+                };
+                l.pop();
+            }
+
             if l.get_field_type_or_nil(arg_n, c"sync", LUA_TBOOLEAN)? {
                 self.sync = l.get_boolean(-1);
                 l.pop();
@@ -61,43 +244,293 @@ impl Query {
             }
         }
 
-        if l.get_field_type_or_nil(arg_n, c"raw", LUA_TBOOLEAN)? {
-            self.raw = l.get_boolean(-1);
+        if l.get_field_type_or_nil(arg_n, c"with_found", LUA_TBOOLEAN)? {
+            self.with_found = l.get_boolean(-1);
             l.pop();
         }
 
-        Ok(())
-    }
+        if l.get_field_type_or_nil(arg_n, c"empty_as_table", LUA_TBOOLEAN)? {
+            self.empty_as_table = l.get_boolean(-1);
+            l.pop();
+        }
 
-    pub fn bind_params(&mut self, l: lua::State) -> Result<()> {
-        for i in 1..=l.len(-1) {
-            l.raw_geti(-1, i);
+        if l.get_field_type_or_nil(arg_n, c"with_index", LUA_TBOOLEAN)? {
+            self.with_index = l.get_boolean(-1);
+            l.pop();
+        }
 
-            match l.lua_type(-1) {
-                LUA_TNUMBER => {
-                    let num = l.to_number(-1);
-                    self.params.push(Param::Number(num as i32));
-                }
-                LUA_TSTRING => {
-                    // SAFETY: We just checked the type
-                    let s = l.get_binary_string(-1).unwrap();
-                    self.params.push(Param::String(s.to_owned()));
-                }
-                LUA_TBOOLEAN => {
-                    let b = l.get_boolean(-1);
-                    self.params.push(Param::Boolean(b));
-                }
-                _ => {
-                    bail!(
-                        "Unsupported type for parameter {}: {}",
-                        i,
-                        l.lua_type_name(-1)
+        if l.get_field_type_or_nil(arg_n, c"shared_columns", LUA_TBOOLEAN)? {
+            self.shared_columns = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"layout", LUA_TSTRING)? {
+            self.columnar = match l.get_string_unchecked(-1).as_ref() {
+                "rows" => false,
+                "columnar" => true,
+                name => bail!("unknown layout '{}', expected rows or columnar", name),
+            };
+            l.pop();
+        }
+
+        l.get_field(arg_n, c"default");
+        if l.lua_type(-1) != LUA_TNIL {
+            l.dereference(self.default);
+            self.default = l.reference();
+        } else {
+            l.pop();
+        }
+
+        // shorthand for coerce = { column = "bool" }, parsed first so coerce wins for a column in both
+        if l.get_field_type_or_nil(arg_n, c"bool_columns", LUA_TTABLE)? {
+            for i in 1..=l.len(-1) {
+                l.raw_geti(-1, i);
+                let column = l.check_string(-1)?.into_owned();
+                l.pop();
+                self.coerce.insert(column, Coerce::Bool);
+            }
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"coerce", LUA_TTABLE)? {
+            self.parse_coerce(l)?;
+            l.pop();
+        }
+
+        // the server only sends the label, without the definition there's no index to map it to
+        l.get_field(arg_n, c"enum_as_index");
+        let labels_missing = l.lua_type(-1) == LUA_TBOOLEAN;
+        l.pop();
+        if labels_missing {
+            bail!("enum_as_index needs the labels of each column, select `column + 0` to get the index without them");
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"enum_as_index", LUA_TTABLE)? {
+            l.push_nil();
+            while l.next(-2) != 0 {
+                let column = l.check_string(-2)?.into_owned();
+                l.check_table(-1)?;
+                let mut labels = Vec::new();
+                for i in 1..=l.len(-1) {
+                    l.raw_geti(-1, i);
+                    if l.lua_type(-1) != LUA_TSTRING {
+                        bail!(
+                            "enum_as_index labels of column '{}' must be strings",
+                            column
+                        );
+                    }
+                    labels.push(
+                        l.get_binary_string(-1)
+                            .unwrap_or_default()
+                            .to_vec(),
                     );
+                    l.pop();
+                }
+                self.enum_as_index.insert(column, labels);
+                l.pop();
+            }
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"parse_numeric_strings", LUA_TBOOLEAN)? {
+            self.parse_numeric_strings = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"format", LUA_TSTRING)? {
+            self.msgpack = match l.get_string_unchecked(-1).as_ref() {
+                "lua" => false,
+                "msgpack" => true,
+                name => bail!("unknown format '{}', expected lua or msgpack", name),
+            };
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"tagged", LUA_TBOOLEAN)? {
+            self.tagged = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"json_ordered", LUA_TBOOLEAN)? {
+            self.json_ordered = l.get_boolean(-1);
+            l.pop();
+        }
+
+        // SET NAMES can't be bound, so only plain charset names get in
+        if l.get_field_type_or_nil(arg_n, c"charset", LUA_TSTRING)? {
+            let charset = l.get_string_unchecked(-1).into_owned();
+            l.pop();
+            if charset.is_empty()
+                || !charset
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+            {
+                bail!("invalid charset: {}", charset);
+            }
+            self.charset = Some(charset);
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"require_rows", LUA_TBOOLEAN)? {
+            self.require_rows = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"ignore_errors", LUA_TTABLE)? {
+            for i in 1..=l.len(-1) {
+                l.raw_geti(-1, i);
+                let code = l.check_number(-1)?;
+                l.pop();
+                if code < 1.0 || code > u16::MAX as f64 || code.fract() != 0.0 {
+                    bail!("ignore_errors must only contain mysql error numbers");
                 }
+                self.ignore_errors.push(code as u16);
             }
+            l.pop();
+        }
 
+        if l.get_field_type_or_nil(arg_n, c"fetch_inserted", LUA_TBOOLEAN)? {
+            self.fetch_inserted = l.get_boolean(-1);
             l.pop();
         }
+
+        if l.get_field_type_or_nil(arg_n, c"blob_encoding", LUA_TSTRING)? {
+            self.blob_base64 = match l.get_string_unchecked(-1).as_ref() {
+                "raw" => false,
+                "base64" => true,
+                name => bail!("unknown blob_encoding '{}', expected raw or base64", name),
+            };
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"vector_as_floats", LUA_TBOOLEAN)? {
+            self.vector_as_floats = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"time_unit", LUA_TSTRING)? {
+            self.time_unit = Some(TimeUnit::from_name(&l.get_string_unchecked(-1))?);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"fields", LUA_TTABLE)? {
+            let mut fields = Vec::new();
+            for i in 1..=l.len(-1) {
+                l.raw_geti(-1, i);
+                fields.push(l.check_string(-1)?.into_owned());
+                l.pop();
+            }
+            self.fields = Some(fields);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"column_case", LUA_TSTRING)? {
+            self.column_case = ColumnCase::from_name(&l.get_string_unchecked(-1))?;
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"case_insensitive_keys", LUA_TBOOLEAN)? {
+            self.case_insensitive_keys = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"key_by", LUA_TSTRING)? {
+            self.key_by = Some(l.get_string_unchecked(-1).into_owned());
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"key_by_unique", LUA_TBOOLEAN)? {
+            self.key_by_unique = l.get_boolean(-1);
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"distinct_by", LUA_TSTRING)? {
+            self.distinct_by = Some(l.get_string_unchecked(-1).into_owned());
+            l.pop();
+        }
+
+        if l.get_field_type_or_nil(arg_n, c"top_n", LUA_TTABLE)? {
+            self.top_n = Some(TopN::parse(l)?);
+            l.pop();
+        }
+
+        Ok(())
+    }
+
+    // for helpers that take either a callback function or a full options table
+    pub fn parse_callback_or_options(&mut self, l: lua::State, arg_n: i32) -> Result<()> {
+        if l.is_function(arg_n) {
+            l.push_value(arg_n);
+            self.callback = l.reference();
+            self.sync = false;
+            Ok(())
+        } else {
+            self.parse_options(l, arg_n, true)
+        }
+    }
+
+    // { column_name = "json"|"bool"|"number"|"string" }, table is at the top of the stack
+    fn parse_coerce(&mut self, l: lua::State) -> Result<()> {
+        l.push_nil();
+        while l.next(-2) != 0 {
+            let column = l.check_string(-2)?.into_owned();
+            let coerce = Coerce::from_name(&l.check_string(-1)?)?;
+            self.coerce.insert(column, coerce);
+            l.pop();
+        }
+        Ok(())
+    }
+
+    pub fn bind_params(&mut self, l: lua::State) -> Result<()> {
+        // # stops at the first nil, `n` (like table.pack sets) makes nils in between bind as NULL
+        let mut count = l.len(-1);
+        if l.get_field_type_or_nil(-1, c"n", LUA_TNUMBER)? {
+            let n = l.to_number(-1);
+            l.pop();
+            if n.fract() != 0.0 || !(0.0..=MAX_PLACEHOLDERS as f64).contains(&n) {
+                return Err(KindError::new(
+                    "invalid_params",
+                    format!(
+                        "params.n must be an integer between 0 and {}",
+                        MAX_PLACEHOLDERS
+                    ),
+                )
+                .into());
+            }
+            count = n as i32;
+        }
+
+        for i in 1..=count {
+            l.raw_geti(-1, i);
+            let param = to_param(l, i)?;
+            self.params.push(param);
+            l.pop();
+        }
+        Ok(())
+    }
+
+    // turns $1/:name placeholders into ? and orders the params to match them
+    fn apply_placeholders(
+        &mut self,
+        style: PlaceholderStyle,
+        named_params: HashMap<String, Param>,
+    ) -> Result<()> {
+        let (query, placeholders) = placeholder::rewrite(&self.query, style)?;
+        let mut params = Vec::with_capacity(placeholders.len());
+        for placeholder in placeholders {
+            let param = match placeholder {
+                Placeholder::Index(idx) => match self.params.get(idx - 1) {
+                    Some(param) => param.clone(),
+                    None => bail!("missing parameter for ${}", idx),
+                },
+                Placeholder::Name(name) => match named_params.get(&name) {
+                    Some(param) => param.clone(),
+                    None => bail!("missing parameter for :{}", name),
+                },
+            };
+            params.push(param);
+        }
+        self.query = query;
+        self.params = params;
         Ok(())
     }
 
@@ -111,20 +544,176 @@ impl Query {
 
     #[inline]
     pub async fn start<'q>(&mut self, conn: &'q mut MySqlConnection) -> Result<QueryResult> {
+        tokio::select! {
+            res = self.start_inner(conn) => res,
+            _ = shutdown_signal() => Err(KindError::new(
+                "runtime_shutdown",
+                "query was cancelled because the server is shutting down",
+            )
+            .into()),
+        }
+    }
+
+    async fn start_inner(&mut self, conn: &mut MySqlConnection) -> Result<QueryResult> {
+        // taken once the connection is ours, so a query waiting on a busy connection doesn't hold a permit
+        let _permit = acquire_query_permit().await;
+
+        if let QueryType::Pipeline = self.r#type {
+            let mut results = Vec::with_capacity(self.pipeline.len());
+            for query in self.pipeline.iter_mut() {
+                query.debug_sql = self.debug_sql;
+                query.retry_after_reconnect = self.retry_after_reconnect;
+                results.push(query.run_with_retries(conn).await?);
+            }
+            return Ok(QueryResult::Pipeline(results));
+        }
+
+        self.run_with_retries(conn).await
+    }
+
+    async fn run_with_retries(&mut self, conn: &mut MySqlConnection) -> Result<QueryResult> {
+        match self.charset.clone() {
+            Some(charset) => self.run_with_charset(conn, &charset).await,
+            None => self.run_retrying(conn).await,
+        }
+    }
+
+    // the session's charset is read first so it can be put back after, that's 3 more round trips
+    async fn run_with_charset(
+        &mut self,
+        conn: &mut MySqlConnection,
+        charset: &str,
+    ) -> Result<QueryResult> {
+        let (charset_before, collation_before): (String, String) =
+            sqlx::query_as("SELECT @@character_set_client, @@collation_connection")
+                .fetch_one(&mut *conn)
+                .await?;
+        let restore = format!("SET NAMES {} COLLATE {}", charset_before, collation_before);
+        self.charset_restore = Some(restore.clone());
+        conn.execute(format!("SET NAMES {}", charset).as_str())
+            .await?;
+
+        let res = self.run_retrying(conn).await;
+
+        // the query's own error is the one worth reporting
+        let restored = conn.execute(restore.as_str()).await;
+        if restored.is_ok() {
+            self.charset_restore = None;
+        }
+        let res = res?;
+        restored?;
+        Ok(res)
+    }
+
+    // the SET NAMES that puts the session's charset back, if this query (or one in its pipeline)
+    // couldn't do it itself
+    pub fn take_charset_restore(&mut self) -> Option<String> {
+        self.pipeline
+            .iter_mut()
+            .filter_map(|query| query.charset_restore.take())
+            .next()
+            .or(self.charset_restore.take())
+    }
+
+    async fn run_retrying(&mut self, conn: &mut MySqlConnection) -> Result<QueryResult> {
+        let mut attempts = 0;
+        loop {
+            match self.run(conn).await {
+                Err(e) if attempts < self.retry_on_deadlock && is_lock_error(&e) => {
+                    attempts += 1;
+                    tokio::time::sleep(DEADLOCK_RETRY_BACKOFF * attempts).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn run(&mut self, conn: &mut MySqlConnection) -> Result<QueryResult> {
+        let res = self.run_query(conn).await?;
+        match res {
+            QueryResult::Execute(info) if self.fetch_inserted => {
+                let row = inserted::fetch(conn, &self.query, &info).await?;
+                Ok(QueryResult::Inserted(info, row))
+            }
+            QueryResult::Rows(rows) => {
+                let rows = match &self.distinct_by {
+                    Some(distinct_by) => distinct_rows(rows, distinct_by)?,
+                    None => rows,
+                };
+                let rows = match &self.top_n {
+                    Some(top_n) => top_n.apply(rows)?,
+                    None => rows,
+                };
+                if self.require_rows && rows.is_empty() {
+                    return Err(KindError::new("no_rows", "query returned no rows").into());
+                }
+                match &self.total_query {
+                    Some((total_query, params)) => {
+                        let total = fetch_total(conn, total_query, params, self.raw).await?;
+                        Ok(QueryResult::Page(rows, total))
+                    }
+                    None => Ok(QueryResult::Rows(rows)),
+                }
+            }
+            res => Ok(res),
+        }
+    }
+
+    async fn run_query(&mut self, conn: &mut MySqlConnection) -> Result<QueryResult> {
+        if self.debug_sql {
+            self.log_sql();
+        }
+
         let r#type = &self.r#type;
-        if self.raw {
-            handle_query(self.query.as_str(), conn, r#type).await
+        let max_bytes = self.max_result_bytes;
+        let res = if self.raw {
+            handle_query(self.query.as_str(), conn, r#type, max_bytes).await
         } else {
-            let mut query = sqlx::query(self.query.as_str());
-            for param in self.params.drain(..) {
-                match param {
-                    Param::Number(n) => query = query.bind(n),
-                    Param::String(s) => query = query.bind(s),
-                    Param::Boolean(b) => query = query.bind(b),
-                };
+            check_param_count(&self.query, self.params.len())?;
+
+            // params are needed again if the query gets retried
+            let params = if self.retry_on_deadlock > 0 || self.retry_after_reconnect {
+                self.params.clone()
+            } else {
+                std::mem::take(&mut self.params)
+            };
+
+            let query = build_query(self.query.as_str(), params).persistent(!self.no_cache);
+            handle_query(query, conn, r#type, max_bytes).await
+        };
+
+        match res {
+            Err(e) if mysql_error_number(&e).is_some_and(|n| self.ignore_errors.contains(&n)) => {
+                Ok(empty_result(r#type))
             }
-            handle_query(query, conn, r#type).await
+            res => res,
+        }
+    }
+
+    // SET NAMES, SET CHARACTER SET or a new sql_mode change how strings have to be escaped
+    // a false positive only costs a round trip, so this just looks for the words
+    pub fn changes_escaping(&self) -> bool {
+        let touches = |sql: &str| {
+            let sql = sql.to_ascii_lowercase();
+            sql.contains("set")
+                && ["names", "character", "charset", "sql_mode"]
+                    .iter()
+                    .any(|word| sql.contains(word))
+        };
+        touches(&self.query)
+            || self
+                .pipeline
+                .iter()
+                .any(|query| touches(&query.query))
+    }
+
+    fn log_sql(&self) {
+        if self.raw || self.params.is_empty() {
+            print_goobie!("SQL: {}", self.query);
+            return;
         }
+        let params: Vec<String> = self.params.iter().map(Param::summary).collect();
+        print_goobie!("SQL: {} [{}]", self.query, params.join(", "));
     }
 
     pub fn process_result(
@@ -133,12 +722,12 @@ impl Query {
         res: Result<QueryResult>,
         traceback: Option<&str>,
     ) -> i32 {
-        let res = match res {
-            Ok(QueryResult::Execute(info)) => process_info(l, info),
-            Ok(QueryResult::Row(row)) => process_row(l, row),
-            Ok(QueryResult::Rows(rows)) => process_rows(l, &rows),
-            Err(e) => Err(e),
-        };
+        let res = res.and_then(|res| self.push_result(l, res));
+
+        l.dereference(self.default);
+        for query in &self.pipeline {
+            l.dereference(query.default);
+        }
 
         let (returns_count, err_msg) = match res {
             Ok(0) => {
@@ -174,12 +763,205 @@ impl Query {
 
         0
     }
+
+    fn push_result(&self, l: lua::State, res: QueryResult) -> Result<i32> {
+        match res {
+            QueryResult::Execute(info) => {
+                process_info(l, info)?;
+                // only a Fetch method that ran a DML ends up here
+                if !matches!(self.r#type, QueryType::Execute | QueryType::ExecuteMany) {
+                    l.push_string("dml_in_fetch");
+                    l.set_field(-2, c"warning");
+                }
+                Ok(1)
+            }
+            QueryResult::Inserted(info, row) => {
+                process_info(l, info)?;
+                match row {
+                    Some(row) => push_row_to_lua(l, &row, self)?,
+                    None => l.push_nil(),
+                }
+                l.set_field(-2, c"inserted");
+                Ok(1)
+            }
+            QueryResult::Row(row) => match self.r#type {
+                QueryType::FetchValue => process_value(l, row, self),
+                QueryType::FetchRow => process_row_values(l, row, self),
+                _ => process_row(l, row, self),
+            },
+            QueryResult::Rows(rows) => match &self.keyset {
+                Some((key_column, limit)) => {
+                    process_rows(l, &rows, self)?;
+                    // a short page is the last one, nil tells there is nothing after it
+                    match rows.last() {
+                        Some(row) if rows.len() >= *limit => {
+                            push_named_value(l, row, key_column, self)?
+                        }
+                        _ => l.push_nil(),
+                    }
+                    Ok(2)
+                }
+                None if self.msgpack => {
+                    let rows: Vec<_> = rows.iter().map(value::MsgpackRow).collect();
+                    l.push_binary_string(&rmp_serde::to_vec(&rows)?);
+                    Ok(1)
+                }
+                None => process_rows(l, &rows, self),
+            },
+            QueryResult::Page(rows, total) => {
+                process_rows(l, &rows, self)?;
+                l.push_number(total);
+                Ok(2)
+            }
+            QueryResult::Many(results) => {
+                l.create_table(results.len() as i32, 0);
+                for (idx, res) in results.into_iter().enumerate() {
+                    self.push_result(l, res)?;
+                    l.raw_seti(-2, idx as i32 + 1);
+                }
+                Ok(1)
+            }
+            QueryResult::Count(rows) => {
+                l.push_number(rows);
+                Ok(1)
+            }
+            QueryResult::Pipeline(results) => {
+                l.create_table(results.len() as i32, 0);
+                for (idx, (res, query)) in results
+                    .into_iter()
+                    .zip(&self.pipeline)
+                    .enumerate()
+                {
+                    query.push_result(l, res)?;
+                    l.raw_seti(-2, idx as i32 + 1);
+                }
+                Ok(1)
+            }
+        }
+    }
+}
+
+// keeps the first row for each value of the column, values are compared as the server sent them
+fn distinct_rows(rows: Vec<MySqlRow>, column: &str) -> Result<Vec<MySqlRow>> {
+    let idx = match rows.first() {
+        Some(row) => match row
+            .columns()
+            .iter()
+            .position(|c| c.name() == column)
+        {
+            Some(idx) => idx,
+            None => bail!("distinct_by column '{}' is not in the result", column),
+        },
+        None => return Ok(rows),
+    };
+
+    let mut seen = HashSet::new();
+    let mut distinct = Vec::with_capacity(rows.len());
+    for row in rows {
+        let key: Option<Vec<u8>> = row
+            .try_get_unchecked::<Option<&[u8]>, _>(idx)?
+            .map(<[u8]>::to_vec);
+        if seen.insert(key) {
+            distinct.push(row);
+        }
+    }
+    Ok(distinct)
+}
+
+// value at the top of the stack
+pub fn to_param(l: lua::State, name: impl std::fmt::Display) -> Result<Param> {
+    let param = match l.lua_type(-1) {
+        LUA_TNIL => Param::Null,
+        LUA_TNUMBER => number_param(l.to_number(-1), &name)?,
+        LUA_TSTRING => {
+            // SAFETY: We just checked the type
+            let s = l.get_binary_string(-1).unwrap();
+            Param::String(s.to_owned())
+        }
+        LUA_TBOOLEAN => {
+            let b = l.get_boolean(-1);
+            Param::Boolean(b)
+        }
+        LUA_TUSERDATA if int::is_param(l, -1) => int::to_param(l, -1)?,
+        LUA_TUSERDATA if json::is_param(l, -1) => {
+            let s = json::param_to_string(l, -1)?;
+            Param::Json(s)
+        }
+        _ => {
+            bail!(
+                "Unsupported type for parameter {}: {}",
+                name,
+                l.lua_type_name(-1)
+            );
+        }
+    };
+    Ok(param)
+}
+
+// whole numbers get the narrowest type that holds them, so INT UNSIGNED values above i32::MAX don't get
+// cut off, anything that would have to be rounded or wrapped to fit is an error instead
+fn number_param(num: f64, name: &impl std::fmt::Display) -> Result<Param> {
+    // 2^63 and 2^64, i64::MAX and u64::MAX themselves aren't representable as f64
+    const I64_END: f64 = 9223372036854775808.0;
+    const U64_END: f64 = 18446744073709551616.0;
+    let param = if !num.is_finite() {
+        bail!("parameter {} is not a finite number: {}", name, num);
+    } else if num.fract() != 0.0 {
+        Param::Float(num)
+    } else if num >= i32::MIN as f64 && num <= i32::MAX as f64 {
+        Param::Number(num as i32)
+    } else if num >= 0.0 && num <= u32::MAX as f64 {
+        Param::Unsigned(num as u32)
+    } else if (-I64_END..I64_END).contains(&num) {
+        Param::BigInt(num as i64)
+    } else if (0.0..U64_END).contains(&num) {
+        Param::BigUnsigned(num as u64)
+    } else {
+        bail!(
+            "parameter {} is out of range of a 64 bit integer: {}",
+            name,
+            num
+        );
+    };
+    Ok(param)
+}
+
+// { name = value }, table is at the top of the stack
+fn bind_named_params(l: lua::State) -> Result<HashMap<String, Param>> {
+    let mut params = HashMap::new();
+    l.push_nil();
+    while l.next(-2) != 0 {
+        let name = l.check_string(-2)?.into_owned();
+        let param = to_param(l, &name)?;
+        params.insert(name, param);
+        l.pop();
+    }
+    Ok(params)
+}
+
+pub fn build_query(query: &str, params: Params) -> SqlxQuery<'_, MySql, MySqlArguments> {
+    let mut query = sqlx::query(query);
+    for param in params {
+        match param {
+            Param::Null => query = query.bind(None::<i32>),
+            Param::Number(n) => query = query.bind(n),
+            Param::Float(n) => query = query.bind(n),
+            Param::Unsigned(n) => query = query.bind(n),
+            Param::BigInt(n) => query = query.bind(n),
+            Param::BigUnsigned(n) => query = query.bind(n),
+            Param::String(s) => query = query.bind(s),
+            Param::Boolean(b) => query = query.bind(b),
+            Param::Json(s) => query = query.bind(s),
+        };
+    }
+    query
 }
 
 async fn handle_query<'q, E>(
     query: E,
     conn: &'q mut MySqlConnection,
     query_type: &QueryType,
+    max_bytes: Option<usize>,
 ) -> Result<QueryResult>
 where
     E: 'q + sqlx::Execute<'q, sqlx::MySql>,
@@ -189,13 +971,326 @@ where
             let info = conn.execute(query).await?;
             Ok(QueryResult::Execute(info))
         }
-        QueryType::FetchAll => {
-            let rows = conn.fetch_all(query).await?;
-            Ok(QueryResult::Rows(rows))
+        QueryType::FetchAll => match fetch_rows(query, conn, max_bytes, false).await? {
+            Either::Left(info) => Ok(QueryResult::Execute(info)),
+            Either::Right(rows) => Ok(QueryResult::Rows(rows)),
+        },
+        QueryType::FetchOne | QueryType::FetchValue | QueryType::FetchRow => {
+            match fetch_rows(query, conn, max_bytes, true).await? {
+                Either::Left(info) => Ok(QueryResult::Execute(info)),
+                Either::Right(rows) => Ok(QueryResult::Row(rows.into_iter().next())),
+            }
+        }
+        QueryType::ExecuteMany => {
+            let sql = query.sql();
+            let mut stream = conn.fetch_many(query);
+            let mut results = Vec::new();
+            let mut rows = Vec::new();
+            let mut total_bytes = 0;
+            // every statement ends with its info, rows before it belong to it
+            while let Some(item) = stream.try_next().await? {
+                match item {
+                    Either::Left(info) if rows.is_empty() => {
+                        results.push(QueryResult::Execute(info))
+                    }
+                    Either::Left(_) => results.push(QueryResult::Rows(std::mem::take(&mut rows))),
+                    Either::Right(row) => {
+                        if let Some(max_bytes) = max_bytes {
+                            total_bytes += estimate_row_size(&row);
+                            check_result_size(total_bytes, max_bytes)?;
+                        }
+                        rows.push(row);
+                    }
+                }
+            }
+            if !rows.is_empty() {
+                results.push(QueryResult::Rows(rows));
+            }
+            drop(stream);
+            mark_empty_selects(conn, sql, &mut results).await;
+            Ok(QueryResult::Many(results))
         }
-        QueryType::FetchOne => {
-            let row = conn.fetch_optional(query).await?;
-            Ok(QueryResult::Row(row))
+        QueryType::Pipeline => bail!("a pipeline can't run as a single query"),
+    }
+}
+
+// a SELECT that returned no rows comes back as just its info, same as an UPDATE that matched nothing,
+// so statements that could be one are described to see if they have columns
+// if the statements can't be lined up with the results (e.g. a CALL returning several) they are left as is
+async fn mark_empty_selects(conn: &mut MySqlConnection, sql: &str, results: &mut [QueryResult]) {
+    let statements = match script::split(sql) {
+        Ok(statements) if statements.len() == results.len() => statements,
+        _ => return,
+    };
+
+    for (statement, result) in statements.iter().zip(results.iter_mut()) {
+        let QueryResult::Execute(info) = result else {
+            continue;
+        };
+        if info.rows_affected() != 0 || info.last_insert_id() != 0 {
+            continue;
         }
+        // only prepares the statement, it doesn't run again
+        if let Ok(describe) = conn.describe(statement.as_str()).await {
+            if !describe.columns().is_empty() {
+                *result = QueryResult::Rows(Vec::new());
+            }
+        }
+    }
+}
+
+// what a query that ran without touching anything returns, for errors in ignore_errors
+fn empty_result(query_type: &QueryType) -> QueryResult {
+    match query_type {
+        QueryType::FetchAll => QueryResult::Rows(Vec::new()),
+        QueryType::FetchOne | QueryType::FetchValue | QueryType::FetchRow => QueryResult::Row(None),
+        QueryType::ExecuteMany => QueryResult::Many(Vec::new()),
+        QueryType::Execute | QueryType::Pipeline => {
+            QueryResult::Execute(MySqlQueryResult::default())
+        }
+    }
+}
+
+async fn fetch_total(
+    conn: &mut MySqlConnection,
+    total_query: &str,
+    params: &Params,
+    raw: bool,
+) -> Result<i64> {
+    let row = if raw {
+        conn.fetch_one(total_query).await?
+    } else {
+        conn.fetch_one(build_query(total_query, params.clone()))
+            .await?
+    };
+    Ok(row.try_get_unchecked(0)?)
+}
+
+// sqlx's error for this doesn't say much, and it's an easy mistake to make
+fn check_param_count(query: &str, provided: usize) -> Result<()> {
+    let expected = placeholder::count(query);
+    if expected != provided {
+        return Err(KindError::new(
+            "param_mismatch",
+            format!(
+                "query expects {} parameters but {} were provided",
+                expected, provided
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn deadlock_retries(n: f64) -> Result<u32> {
+    if !(0.0..=MAX_DEADLOCK_RETRIES as f64).contains(&n) || n.fract() != 0.0 {
+        bail!(
+            "retry_on_deadlock must be an integer from 0 to {}",
+            MAX_DEADLOCK_RETRIES
+        );
+    }
+    Ok(n as u32)
+}
+
+// deadlocks and lock wait timeouts, both are safe to retry
+fn is_lock_error(e: &anyhow::Error) -> bool {
+    matches!(mysql_error_number(e), Some(1213 | 1205))
+}
+
+// stops pulling rows as soon as the result gets too big
+// a statement that returned no rows but changed some is a DML that went through a Fetch method,
+// its info is returned instead of an empty result so the mistake doesn't go unnoticed
+async fn fetch_rows<'q, E>(
+    query: E,
+    conn: &'q mut MySqlConnection,
+    max_bytes: Option<usize>,
+    first_only: bool,
+) -> Result<Either<MySqlQueryResult, Vec<MySqlRow>>>
+where
+    E: 'q + sqlx::Execute<'q, sqlx::MySql>,
+{
+    let mut stream = conn.fetch_many(query);
+    let mut rows = Vec::new();
+    let mut info = MySqlQueryResult::default();
+    let mut total_bytes = 0;
+    while let Some(item) = stream.try_next().await? {
+        match item {
+            Either::Left(res) => info.extend([res]),
+            Either::Right(row) => {
+                if let Some(max_bytes) = max_bytes {
+                    total_bytes += estimate_row_size(&row);
+                    check_result_size(total_bytes, max_bytes)?;
+                }
+                rows.push(row);
+                if first_only {
+                    break;
+                }
+            }
+        }
+    }
+
+    if rows.is_empty() && info.rows_affected() > 0 {
+        print_goobie!(
+            "A Fetch query affected {} rows but returned none, use Execute for INSERT/UPDATE/DELETE",
+            info.rows_affected()
+        );
+        return Ok(Either::Left(info));
+    }
+
+    Ok(Either::Right(rows))
+}
+
+fn check_result_size(total_bytes: usize, max_bytes: usize) -> Result<()> {
+    if total_bytes > max_bytes {
+        return Err(KindError::new(
+            "result_too_large",
+            format!("result set exceeded max_result_bytes ({} bytes)", max_bytes),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Executor as _, Row as _};
+
+    use super::{
+        build_query, deadlock_retries, number_param, param::Param, Query, QueryResult, QueryType,
+    };
+    use crate::{error::mysql_error_number, test_db};
+
+    #[test]
+    fn numbers_get_the_narrowest_type() {
+        let param = |n: f64| number_param(n, &1).unwrap();
+        assert!(matches!(param(1.5), Param::Float(n) if n == 1.5));
+        assert!(matches!(param(-2147483648.0), Param::Number(i32::MIN)));
+        assert!(matches!(param(3000000000.0), Param::Unsigned(3000000000)));
+        assert!(matches!(param(-3000000000.0), Param::BigInt(-3000000000)));
+        assert!(matches!(
+            param(9007199254740992.0),
+            Param::BigInt(9007199254740992)
+        ));
+        assert!(matches!(
+            param(9223372036854775808.0),
+            Param::BigUnsigned(9223372036854775808)
+        ));
+        for n in [
+            f64::NAN,
+            f64::INFINITY,
+            18446744073709551616.0,
+            -9223372036854777856.0,
+        ] {
+            assert!(number_param(n, &1).is_err(), "{}", n);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn binds_int_unsigned_above_i32() {
+        test_db::run(async {
+            let mut conn = test_db::connect().await;
+            conn.execute("CREATE TEMPORARY TABLE goobie_test_unsigned (n INT UNSIGNED NOT NULL)")
+                .await
+                .unwrap();
+
+            let n = number_param(3000000000.0, &1).unwrap();
+            build_query(
+                "INSERT INTO goobie_test_unsigned (n) VALUES (?)",
+                vec![n.clone()],
+            )
+            .execute(&mut conn)
+            .await
+            .unwrap();
+            let found: u32 = build_query("SELECT n FROM goobie_test_unsigned WHERE n = ?", vec![n])
+                .fetch_one(&mut conn)
+                .await
+                .unwrap()
+                .get(0);
+            assert_eq!(found, 3000000000);
+        });
+    }
+
+    #[test]
+    fn deadlock_retries_are_capped() {
+        assert_eq!(deadlock_retries(0.0).unwrap(), 0);
+        assert_eq!(deadlock_retries(3.0).unwrap(), 3);
+        for n in [-1.0, 1.5, f64::NAN, 1e12, f64::INFINITY] {
+            assert!(deadlock_retries(n).is_err(), "{}", n);
+        }
+    }
+
+    // the procedure fails with a deadlock until it has been called fail_times times
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn retries_deadlocks_up_to_the_limit() {
+        test_db::run(async {
+            let mut conn = test_db::connect().await;
+            conn.execute("DROP PROCEDURE IF EXISTS goobie_test_deadlock")
+                .await
+                .unwrap();
+            conn.execute(
+                "CREATE PROCEDURE goobie_test_deadlock(fail_times INT)
+                BEGIN
+                    SET @goobie_attempts = @goobie_attempts + 1;
+                    IF @goobie_attempts <= fail_times THEN
+                        SIGNAL SQLSTATE '40001' SET MYSQL_ERRNO = 1213, MESSAGE_TEXT = 'Deadlock found';
+                    END IF;
+                    SELECT @goobie_attempts AS attempts;
+                END",
+            )
+            .await
+            .unwrap();
+
+            let call = |retries| {
+                let mut query = Query::new(
+                    "CALL goobie_test_deadlock(2)".to_string(),
+                    QueryType::FetchOne,
+                );
+                query.retry_on_deadlock = retries;
+                query
+            };
+
+            conn.execute("SET @goobie_attempts = 0")
+                .await
+                .unwrap();
+            match call(2).start(&mut conn).await.unwrap() {
+                QueryResult::Row(Some(row)) => assert_eq!(row.get::<i64, _>(0), 3),
+                res => panic!("unexpected result: {:?}", res),
+            }
+
+            conn.execute("SET @goobie_attempts = 0")
+                .await
+                .unwrap();
+            let e = call(1).start(&mut conn).await.err().unwrap();
+            assert_eq!(mysql_error_number(&e), Some(1213));
+            let attempts: i64 = sqlx::query_scalar("SELECT @goobie_attempts")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(attempts, 2);
+
+            conn.execute("DROP PROCEDURE goobie_test_deadlock")
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn large_blobs_trip_max_result_bytes() {
+        test_db::run(async {
+            let mut conn = test_db::connect().await;
+
+            let sql = "SELECT REPEAT('x', 65536) AS blob_col UNION ALL SELECT REPEAT('y', 65536)";
+            let mut query = Query::new(sql.to_string(), QueryType::FetchAll);
+            query.max_result_bytes = Some(100_000);
+            let e = query.start(&mut conn).await.err().unwrap();
+            assert_eq!(test_db::kind(&e), Some("result_too_large"));
+
+            let mut query = Query::new(sql.to_string(), QueryType::FetchAll);
+            query.max_result_bytes = Some(200_000);
+            assert!(query.start(&mut conn).await.is_ok());
+        });
     }
 }
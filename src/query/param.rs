@@ -1,6 +1,33 @@
 #[derive(Debug, Clone)]
 pub enum Param {
+    Null,
     Number(i32),
+    // numbers with a fraction
+    Float(f64),
+    // whole numbers that don't fit in an i32
+    Unsigned(u32),
+    BigInt(i64),
+    // from goobie_mysql.Int, values past i64::MAX
+    BigUnsigned(u64),
     String(Vec<u8>),
     Boolean(bool),
+    // bound as text, mysql refuses to build a JSON value out of binary strings
+    Json(String),
+}
+
+impl Param {
+    // for debug_sql logs, only the type and length so passwords, tokens or ids never end up in the console
+    pub fn summary(&self) -> String {
+        match self {
+            Param::Null => "NULL".to_string(),
+            Param::Number(_)
+            | Param::Float(_)
+            | Param::Unsigned(_)
+            | Param::BigInt(_)
+            | Param::BigUnsigned(_) => "<number>".to_string(),
+            Param::Boolean(_) => "<boolean>".to_string(),
+            Param::String(s) => format!("<string, {} bytes>", s.len()),
+            Param::Json(s) => format!("<json, {} bytes>", s.len()),
+        }
+    }
 }
@@ -0,0 +1,250 @@
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Question, // ?
+    Numbered, // $1, $2
+    Named,    // :name
+}
+
+impl Style {
+    pub fn from_name(name: &str) -> Result<Self> {
+        Ok(match name {
+            "question" => Style::Question,
+            "numbered" => Style::Numbered,
+            "named" => Style::Named,
+            _ => bail!(
+                "unknown placeholder style '{}', expected question, numbered or named",
+                name
+            ),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Placeholder {
+    Index(usize),
+    Name(String),
+}
+
+// rewrites $1/:name placeholders into ? and returns which param each ? refers to, in order
+// anything inside strings, quoted identifiers and comments is left alone
+pub fn rewrite(query: &str, style: Style) -> Result<(String, Vec<Placeholder>)> {
+    let bytes = query.as_bytes();
+    let mut out = String::with_capacity(query.len());
+    let mut placeholders = Vec::new();
+    let mut copied = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if let Some(end) = skip_literal(bytes, i) {
+            i = end;
+            continue;
+        }
+
+        match bytes[i] {
+            b'?' => bail!(
+                "? placeholders can't be mixed with {:?} placeholders",
+                style
+            ),
+            b'$' if style == Style::Numbered => {
+                let len = bytes[i + 1..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+                if len == 0 {
+                    i += 1;
+                    continue;
+                }
+                let index: usize = query[i + 1..i + 1 + len].parse()?;
+                if index == 0 {
+                    bail!("numbered placeholders start at $1");
+                }
+                out.push_str(&query[copied..i]);
+                out.push('?');
+                placeholders.push(Placeholder::Index(index));
+                i += 1 + len;
+                copied = i;
+            }
+            b':' if style == Style::Named => {
+                let starts_name = bytes
+                    .get(i + 1)
+                    .is_some_and(|b| b.is_ascii_alphabetic() || *b == b'_');
+                if !starts_name {
+                    // := and friends
+                    i += 1;
+                    continue;
+                }
+                let len = bytes[i + 1..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_alphanumeric() || **b == b'_')
+                    .count();
+                out.push_str(&query[copied..i]);
+                out.push('?');
+                placeholders.push(Placeholder::Name(query[i + 1..i + 1 + len].to_string()));
+                i += 1 + len;
+                copied = i;
+            }
+            _ => i += 1,
+        }
+    }
+
+    out.push_str(&query[copied..]);
+    Ok((out, placeholders))
+}
+
+// number of ? placeholders in the query
+pub fn count(query: &str) -> usize {
+    let bytes = query.as_bytes();
+    let mut count = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some(end) = skip_literal(bytes, i) {
+            i = end;
+            continue;
+        }
+        if bytes[i] == b'?' {
+            count += 1;
+        }
+        i += 1;
+    }
+    count
+}
+
+// whether the query has an ORDER BY of its own, one inside parentheses (a subquery, OVER (...)) doesn't count
+pub fn has_order_by(query: &str) -> bool {
+    let bytes = query.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some(end) = skip_literal(bytes, i) {
+            i = end;
+            continue;
+        }
+        match bytes[i] {
+            b'(' => depth += 1,
+            b')' => depth = depth.saturating_sub(1),
+            _ if depth == 0 && starts_order_by(bytes, i) => return true,
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+fn starts_order_by(bytes: &[u8], start: usize) -> bool {
+    let is_word = |b: &u8| b.is_ascii_alphanumeric() || *b == b'_';
+    let rest = &bytes[start..];
+    if start > 0 && is_word(&bytes[start - 1])
+        || !rest
+            .get(..5)
+            .is_some_and(|w| w.eq_ignore_ascii_case(b"order"))
+    {
+        return false;
+    }
+    let rest = &rest[5..];
+    let spaces = rest
+        .iter()
+        .take_while(|b| b.is_ascii_whitespace())
+        .count();
+    let rest = &rest[spaces..];
+    spaces > 0
+        && rest
+            .get(..2)
+            .is_some_and(|w| w.eq_ignore_ascii_case(b"by"))
+        && !rest.get(2).is_some_and(is_word)
+}
+
+// if a string, quoted identifier or comment starts at `start`, returns where it ends
+pub(super) fn skip_literal(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start;
+    match bytes[i] {
+        quote @ (b'\'' | b'"' | b'`') => {
+            i += 1;
+            while i < bytes.len() && bytes[i] != quote {
+                if bytes[i] == b'\\' && quote != b'`' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            Some(i + 1)
+        }
+        b'#' => Some(skip_line(bytes, i)),
+        b'-' if bytes[i..].starts_with(b"-- ") => Some(skip_line(bytes, i)),
+        b'/' if bytes[i..].starts_with(b"/*") => Some(
+            bytes[i + 2..]
+                .windows(2)
+                .position(|w| w == b"*/")
+                .map_or(bytes.len(), |end| i + 2 + end + 2),
+        ),
+        _ => None,
+    }
+}
+
+pub(super) fn skip_line(bytes: &[u8], start: usize) -> usize {
+    match bytes[start..].iter().position(|b| *b == b'\n') {
+        Some(end) => start + end + 1,
+        None => bytes.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_numbered() {
+        let (query, placeholders) = rewrite("SELECT $2, '$1', $1", Style::Numbered).unwrap();
+        assert_eq!(query, "SELECT ?, '$1', ?");
+        assert!(matches!(
+            placeholders.as_slice(),
+            [Placeholder::Index(2), Placeholder::Index(1)]
+        ));
+        assert!(rewrite("SELECT $0", Style::Numbered).is_err());
+    }
+
+    #[test]
+    fn rewrites_named() {
+        let (query, placeholders) = rewrite(
+            "SELECT * FROM t WHERE a = :name AND @b := 1 AND c = ':x' -- :y",
+            Style::Named,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            "SELECT * FROM t WHERE a = ? AND @b := 1 AND c = ':x' -- :y"
+        );
+        assert!(matches!(placeholders.as_slice(), [Placeholder::Name(name)] if name == "name"));
+    }
+
+    #[test]
+    fn question_marks_cant_be_mixed() {
+        assert!(rewrite("SELECT ?, :a", Style::Named).is_err());
+    }
+
+    #[test]
+    fn counts_only_real_placeholders() {
+        assert_eq!(count("SELECT ?, '?', `?`, \"?\" -- ?\n, ? /* ? */ # ?"), 2);
+        assert_eq!(count("SELECT 'it\\'s ?', ?"), 1);
+        assert_eq!(count("SELECT 1"), 0);
+    }
+
+    #[test]
+    fn finds_only_the_outer_order_by() {
+        assert!(has_order_by("SELECT * FROM t ORDER BY id"));
+        assert!(has_order_by("SELECT * FROM t order\n  by id DESC"));
+        assert!(has_order_by(
+            "(SELECT a FROM t) UNION (SELECT a FROM u) ORDER BY a"
+        ));
+        assert!(!has_order_by("SELECT * FROM t"));
+        assert!(!has_order_by(
+            "SELECT * FROM (SELECT * FROM t ORDER BY id) AS s"
+        ));
+        assert!(!has_order_by(
+            "SELECT ROW_NUMBER() OVER (ORDER BY id) FROM t"
+        ));
+        assert!(!has_order_by("SELECT 'ORDER BY' FROM t -- ORDER BY id"));
+        assert!(!has_order_by("SELECT reorder by_col FROM t"));
+        assert!(!has_order_by("SELECT * FROM t ORDER BYTE"));
+    }
+}
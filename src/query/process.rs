@@ -1,17 +1,55 @@
+use std::borrow::Cow;
+
 use anyhow::{bail, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
 use gmod::*;
 use sqlx::{
     mysql::{MySqlQueryResult, MySqlRow},
-    types::{
-        chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc},
-        Decimal,
-    },
+    types::chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc},
     Column, Row, TypeInfo, ValueRef as _,
 };
 
+use super::{
+    result,
+    value::{decode, ColumnValue},
+    Query,
+};
+
+// how column names are turned into row keys, columns that end up with the same name overwrite each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnCase {
+    Original,
+    Lower,
+    Upper,
+}
+
+impl ColumnCase {
+    pub fn from_name(name: &str) -> Result<Self> {
+        Ok(match name {
+            "original" => ColumnCase::Original,
+            "lower" => ColumnCase::Lower,
+            "upper" => ColumnCase::Upper,
+            _ => bail!(
+                "unknown column_case '{}', expected original, lower or upper",
+                name
+            ),
+        })
+    }
+
+    pub fn apply(self, name: &str) -> Cow<'_, str> {
+        match self {
+            ColumnCase::Original => Cow::Borrowed(name),
+            ColumnCase::Lower => Cow::Owned(name.to_lowercase()),
+            ColumnCase::Upper => Cow::Owned(name.to_uppercase()),
+        }
+    }
+}
+
 pub fn process_info(l: lua::State, info: MySqlQueryResult) -> Result<i32> {
     l.create_table(0, 2);
     {
+        // sqlx always connects with CLIENT_FOUND_ROWS, so for UPDATE this counts the rows that matched
+        // the WHERE even if nothing in them changed, the changed count is not passed on by sqlx
         l.push_number(info.rows_affected());
         l.set_field(-2, c"rows_affected");
 
@@ -22,134 +60,674 @@ pub fn process_info(l: lua::State, info: MySqlQueryResult) -> Result<i32> {
     Ok(1)
 }
 
-pub fn process_rows(l: lua::State, rows: &[MySqlRow]) -> Result<i32> {
+pub fn process_rows(l: lua::State, rows: &[MySqlRow], query: &Query) -> Result<i32> {
+    if query.key_value {
+        return process_rows_key_value(l, rows);
+    }
+
+    if query.shared_columns {
+        return process_rows_shared_columns(l, rows, query);
+    }
+
+    if query.columnar {
+        return process_rows_columnar(l, rows, query);
+    }
+
+    if let Some(key_by) = &query.key_by {
+        return process_rows_key_by(l, rows, key_by, query);
+    }
+
     l.create_table(rows.len() as i32, 0);
 
     for (idx, row) in rows.iter().enumerate() {
-        push_row_to_lua(l, row)?;
+        push_row_to_lua(l, row, query)?;
+        push_row_index(l, idx, query);
         l.raw_seti(-2, idx as i32 + 1);
     }
 
     Ok(1)
 }
 
-pub fn process_row(l: lua::State, row: Option<MySqlRow>) -> Result<i32> {
-    match row {
-        Some(row) => {
-            push_row_to_lua(l, &row)?;
-            Ok(1)
+// first column is the key and second is the value, numeric values are pushed as numbers
+fn process_rows_key_value(l: lua::State, rows: &[MySqlRow]) -> Result<i32> {
+    l.create_table(0, rows.len() as i32);
+
+    for row in rows {
+        let key: String = row.try_get_unchecked(0)?;
+        let value: Option<String> = row.try_get_unchecked(1)?;
+        match value {
+            Some(value) => match value.parse::<f64>() {
+                Ok(num) => l.push_number(num),
+                Err(_) => l.push_string(&value),
+            },
+            None => l.push_nil(),
+        }
+        l.set_field(-2, &cstring(&key));
+    }
+
+    Ok(1)
+}
+
+// rows are keyed by the value of a column instead of being an array
+fn process_rows_key_by(
+    l: lua::State,
+    rows: &[MySqlRow],
+    key_by: &str,
+    query: &Query,
+) -> Result<i32> {
+    l.create_table(0, rows.len() as i32);
+
+    for (row_idx, row) in rows.iter().enumerate() {
+        let key_idx = match row
+            .columns()
+            .iter()
+            .position(|c| c.name() == key_by)
+        {
+            Some(idx) => idx,
+            None => bail!("key_by column '{}' is not in the result", key_by),
+        };
+
+        push_plain_value(l, row, key_idx, query)?;
+        if l.lua_type(-1) == LUA_TNIL {
+            l.pop();
+            bail!("key_by column '{}' can't be NULL", key_by);
+        }
+
+        if query.key_by_unique {
+            l.push_value(-1);
+            l.raw_get(-3);
+            let exists = l.lua_type(-1) != LUA_TNIL;
+            l.pop();
+            if exists {
+                l.pop();
+                bail!("duplicate value for key_by column '{}'", key_by);
+            }
+        }
+
+        push_row_to_lua(l, row, query)?;
+        push_row_index(l, row_idx, query);
+        l.raw_set(-3);
+    }
+
+    Ok(1)
+}
+
+// column names are only pushed once, each row is an array of values in the same order as `columns`
+fn process_rows_shared_columns(l: lua::State, rows: &[MySqlRow], query: &Query) -> Result<i32> {
+    l.create_table(0, 2);
+
+    let (names, indexes) = match rows.first() {
+        Some(row) => (column_names(row, query), column_indexes(row, query)),
+        None => (query.fields.clone().unwrap_or_default(), Vec::new()),
+    };
+    l.create_table(names.len() as i32, 0);
+    for (idx, name) in names.iter().enumerate() {
+        l.push_string(name);
+        l.raw_seti(-2, idx as i32 + 1);
+    }
+    l.set_field(-2, c"columns");
+
+    l.create_table(rows.len() as i32, 0);
+    for (row_idx, row) in rows.iter().enumerate() {
+        l.create_table(indexes.len() as i32, 0);
+        for (idx, column_idx) in indexes.iter().enumerate() {
+            match column_idx {
+                Some(column_idx) => push_value(l, row, *column_idx, query)?,
+                None => l.push_nil(),
+            }
+            l.raw_seti(-2, idx as i32 + 1);
+        }
+        l.raw_seti(-2, row_idx as i32 + 1);
+    }
+    l.set_field(-2, c"rows");
+
+    Ok(1)
+}
+
+// one array of values per column, `columns` keeps the order of the columns
+fn process_rows_columnar(l: lua::State, rows: &[MySqlRow], query: &Query) -> Result<i32> {
+    l.create_table(0, 3);
+
+    let (names, indexes) = match rows.first() {
+        Some(row) => (column_names(row, query), column_indexes(row, query)),
+        None => (query.fields.clone().unwrap_or_default(), Vec::new()),
+    };
+    l.create_table(names.len() as i32, 0);
+    for (idx, name) in names.iter().enumerate() {
+        l.push_string(name);
+        l.raw_seti(-2, idx as i32 + 1);
+    }
+    l.set_field(-2, c"columns");
+
+    l.create_table(0, names.len() as i32);
+    for (name, column_idx) in names.iter().zip(&indexes) {
+        l.create_table(rows.len() as i32, 0);
+        if let Some(column_idx) = column_idx {
+            for (row_idx, row) in rows.iter().enumerate() {
+                push_value(l, row, *column_idx, query)?;
+                l.raw_seti(-2, row_idx as i32 + 1);
+            }
         }
-        None => {
-            l.push_nil();
-            Ok(1)
+        l.set_field(-2, &cstring(name));
+    }
+    l.set_field(-2, c"values");
+
+    // NULLs leave holes in the value arrays, so # can't be trusted for the number of rows
+    l.push_number(rows.len() as i32);
+    l.set_field(-2, c"count");
+
+    Ok(1)
+}
+
+// when `fields` is set only those columns are pushed, in that order, the rest are never decoded
+fn column_indexes(row: &MySqlRow, query: &Query) -> Vec<Option<usize>> {
+    match &query.fields {
+        Some(fields) => fields
+            .iter()
+            .map(|field| {
+                row.columns()
+                    .iter()
+                    .position(|c| c.name() == field)
+            })
+            .collect(),
+        None => (0..row.len()).map(Some).collect(),
+    }
+}
+
+fn column_names(row: &MySqlRow, query: &Query) -> Vec<String> {
+    match &query.fields {
+        Some(fields) => fields.clone(),
+        None => row
+            .columns()
+            .iter()
+            .map(|c| query.column_case.apply(c.name()).into_owned())
+            .collect(),
+    }
+}
+
+pub fn process_row(l: lua::State, row: Option<MySqlRow>, query: &Query) -> Result<i32> {
+    let found = row.is_some();
+    match row {
+        Some(row) => push_row_to_lua(l, &row, query)?,
+        None if query.empty_as_table => l.create_table(0, 0),
+        None => l.push_nil(),
+    }
+
+    // a row full of NULLs still returns a table, but this makes it explicit
+    if query.with_found {
+        l.push_boolean(found);
+        return Ok(2);
+    }
+
+    Ok(1)
+}
+
+// each column is pushed as its own value, nothing at all if there is no row
+pub fn process_row_values(l: lua::State, row: Option<MySqlRow>, query: &Query) -> Result<i32> {
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(0),
+    };
+
+    let indexes = column_indexes(&row, query);
+    for column_idx in &indexes {
+        match column_idx {
+            Some(column_idx) => push_value(l, &row, *column_idx, query)?,
+            None => l.push_nil(),
         }
     }
+
+    Ok(indexes.len() as i32)
 }
 
-fn push_row_to_lua(l: lua::State, row: &MySqlRow) -> Result<()> {
+// first column of the row, or the `default` option if there is no row or the value is NULL
+pub fn process_value(l: lua::State, row: Option<MySqlRow>, query: &Query) -> Result<i32> {
+    match row {
+        Some(row) if !row.is_empty() => push_plain_value(l, &row, 0, query)?,
+        _ => l.push_nil(),
+    }
+
+    if l.lua_type(-1) == LUA_TNIL && query.default != LUA_NOREF {
+        l.pop();
+        l.from_reference(query.default);
+    }
+
+    Ok(1)
+}
+
+// rough size of a row, strings and blobs count their length and everything else a fixed size
+pub fn estimate_row_size(row: &MySqlRow) -> usize {
+    let mut size = 0;
+    for (idx, column) in row.columns().iter().enumerate() {
+        match row.try_get_raw(idx) {
+            Ok(value) if !value.is_null() => {}
+            _ => continue,
+        };
+
+        size += match type_name(column.type_info().name()) {
+            "BINARY" | "VARBINARY" | "TINYBLOB" | "BLOB" | "MEDIUMBLOB" | "LONGBLOB" | "CHAR"
+            | "VARCHAR" | "TEXT" | "TINYTEXT" | "MEDIUMTEXT" | "LONGTEXT" | "JSON" | "ENUM"
+            | "SET" => row
+                .try_get_unchecked::<&[u8], _>(idx)
+                .map_or(0, |bytes| bytes.len()),
+            _ => 8,
+        };
+    }
+    size
+}
+
+pub fn push_row_to_lua(l: lua::State, row: &MySqlRow, query: &Query) -> Result<()> {
+    push_row_fields(l, row, query)?;
+    if query.case_insensitive_keys {
+        result::set_case_insensitive(l);
+    }
+    Ok(())
+}
+
+fn push_row_fields(l: lua::State, row: &MySqlRow, query: &Query) -> Result<()> {
+    // only the listed fields end up in the row, missing columns are just nil
+    if let Some(fields) = &query.fields {
+        l.create_table(0, fields.len() as i32);
+        for field in fields {
+            if let Some(idx) = row
+                .columns()
+                .iter()
+                .position(|c| c.name() == field)
+            {
+                push_value(l, row, idx, query)?;
+                l.set_field(-2, &cstring(field));
+            }
+        }
+        return Ok(());
+    }
+
     l.create_table(0, row.len() as i32);
 
-    for column in row.columns() {
-        let column_name = column.name();
-        let column_type = column.type_info().name();
-        push_column_value_to_lua(l, row, column_name, column_type)?;
-        l.set_field(-2, &cstring(column_name));
+    for (idx, column) in row.columns().iter().enumerate() {
+        push_value(l, row, idx, query)?;
+        l.set_field(-2, &cstring(&query.column_case.apply(column.name())));
     }
 
     Ok(())
 }
 
-fn push_column_value_to_lua(
+// position of the row in the result, for the with_index option
+fn push_row_index(l: lua::State, idx: usize, query: &Query) {
+    if query.with_index {
+        l.push_number(idx as i32 + 1);
+        l.set_field(-2, c"__index");
+    }
+}
+
+// DATE, DATETIME and TIMESTAMP columns as a number since the unix epoch instead of a string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Seconds,
+    Millis,
+    Micros,
+}
+
+impl TimeUnit {
+    pub fn from_name(name: &str) -> Result<Self> {
+        Ok(match name {
+            "s" => TimeUnit::Seconds,
+            "ms" => TimeUnit::Millis,
+            "us" => TimeUnit::Micros,
+            _ => bail!("unknown time_unit '{}', expected s, ms or us", name),
+        })
+    }
+
+    // lua numbers are doubles, exact up to 2^53: seconds and milliseconds are exact for every year mysql
+    // allows (1000 to 9999), microseconds only between about 1685 and 2255, past that they get rounded
+    fn since_epoch(self, datetime: DateTime<Utc>) -> i64 {
+        match self {
+            TimeUnit::Seconds => datetime.timestamp(),
+            TimeUnit::Millis => datetime.timestamp_millis(),
+            TimeUnit::Micros => datetime.timestamp_micros(),
+        }
+    }
+}
+
+// VECTOR is sent as its raw storage, little endian f32s one after another
+pub fn decode_vector(bytes: &[u8]) -> Result<Vec<f32>> {
+    if !bytes.len().is_multiple_of(4) {
+        bail!(
+            "VECTOR value is {} bytes, expected a multiple of 4",
+            bytes.len()
+        );
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+fn push_vector(l: lua::State, bytes: &[u8]) -> Result<()> {
+    let floats = decode_vector(bytes)?;
+    l.create_table(floats.len() as i32, 0);
+    for (idx, f) in floats.into_iter().enumerate() {
+        l.push_number(f);
+        l.raw_seti(-2, idx as i32 + 1);
+    }
+    Ok(())
+}
+
+// DATE and DATETIME have no timezone, they are taken as UTC
+fn push_time_since_epoch(
     l: lua::State,
     row: &MySqlRow,
-    column_name: &str,
+    idx: usize,
     column_type: &str,
+    unit: TimeUnit,
 ) -> Result<()> {
-    let value = row.try_get_raw(column_name)?;
-    if value.is_null() {
+    if row.try_get_raw(idx)?.is_null() {
         l.push_nil();
         return Ok(());
     }
 
-    match column_type {
-        "NULL" => l.push_nil(),
-        "BOOLEAN" | "BOOL" => {
-            let b: bool = row.get(column_name);
-            l.push_boolean(b);
-        }
-        "TINYINT" => {
-            let i8: i8 = row.get(column_name);
-            l.push_number(i8);
-        }
-        "SMALLINT" => {
-            let i16: i16 = row.get(column_name);
-            l.push_number(i16);
-        }
-        "INT" | "INTEGER" => {
-            let i32: i32 = row.get(column_name);
-            l.push_number(i32);
-        }
-        "BIGINT" => {
-            let i64: i64 = row.get(column_name);
-            l.push_number(i64);
-        }
-        "TINYINT UNSIGNED" => {
-            let u8: u8 = row.get(column_name);
-            l.push_number(u8);
-        }
-        "SMALLINT UNSIGNED" => {
-            let u16: u16 = row.get(column_name);
-            l.push_number(u16);
-        }
-        "INT UNSIGNED" => {
-            let u32: u32 = row.get(column_name);
-            l.push_number(u32);
-        }
-        "BIGINT UNSIGNED" => {
-            let u64: u64 = row.get(column_name);
-            l.push_number(u64);
-        }
-        "FLOAT" => {
-            let f32: f32 = row.get(column_name);
-            l.push_number(f32);
-        }
-        "DOUBLE" => {
-            let f64: f64 = row.get(column_name);
-            l.push_number(f64);
-        }
-        "DECIMAL" => {
-            let decimal: Decimal = row.get(column_name);
-            l.push_string(&decimal.to_string());
-        }
-        "TIME" => {
-            let time: NaiveTime = row.get(column_name);
-            l.push_string(&time.to_string());
-        }
-        "DATE" => {
-            let date: NaiveDate = row.get(column_name);
-            l.push_string(&date.to_string());
-        }
-        "DATETIME" => {
-            let datetime: NaiveDateTime = row.get(column_name);
-            l.push_string(&datetime.to_string());
-        }
-        "TIMESTAMP" => {
-            let timestamp: DateTime<Utc> = row.get(column_name);
-            l.push_string(&timestamp.to_string());
+    let datetime = match column_type {
+        "DATE" => row
+            .try_get::<NaiveDate, _>(idx)?
+            .and_time(NaiveTime::MIN)
+            .and_utc(),
+        "DATETIME" => row.try_get::<NaiveDateTime, _>(idx)?.and_utc(),
+        _ => row.try_get::<DateTime<Utc>, _>(idx)?,
+    };
+    l.push_number(unit.since_epoch(datetime));
+    Ok(())
+}
+
+pub fn push_named_value(l: lua::State, row: &MySqlRow, name: &str, query: &Query) -> Result<()> {
+    match row
+        .columns()
+        .iter()
+        .position(|c| c.name() == name)
+    {
+        Some(idx) => push_plain_value(l, row, idx, query),
+        None => bail!("column '{}' is not in the result", name),
+    }
+}
+
+// values inside rows, with `tagged` each one is wrapped as {value = ..., type = "INT"}
+fn push_value(l: lua::State, row: &MySqlRow, idx: usize, query: &Query) -> Result<()> {
+    push_plain_value(l, row, idx, query)?;
+    if query.tagged {
+        l.create_table(0, 2);
+        l.insert(-2);
+        l.set_field(-2, c"value");
+        l.push_string(type_name(row.column(idx).type_info().name()));
+        l.set_field(-2, c"type");
+    }
+    Ok(())
+}
+
+// pushes the value applying any per column options from the query
+fn push_plain_value(l: lua::State, row: &MySqlRow, idx: usize, query: &Query) -> Result<()> {
+    let column = row.column(idx);
+    let column_type = type_name(column.type_info().name());
+    if query.blob_base64 && is_binary_type(column_type) {
+        match row.try_get_unchecked::<Option<&[u8]>, _>(idx)? {
+            Some(bytes) => l.push_string(&BASE64_STANDARD.encode(bytes)),
+            None => l.push_nil(),
         }
-        "BINARY" | "VARBINARY" | "TINYBLOB" | "BLOB" | "MEDIUMBLOB" | "LONGBLOB" | "CHAR"
-        | "VARCHAR" | "TEXT" | "TINYTEXT" | "MEDIUMTEXT" | "LONGTEXT" | "JSON" | "ENUM" | "SET" => {
-            let binary: Vec<u8> = row.get(column_name);
-            l.push_binary_string(&binary);
+    } else if query.vector_as_floats && column_type == "VECTOR" {
+        match row.try_get_unchecked::<Option<&[u8]>, _>(idx)? {
+            Some(bytes) => push_vector(l, bytes)?,
+            None => l.push_nil(),
         }
-        "BIT" => {
-            // figure out what to push, string or a vector or a number
-            bail!("unsupported type: {:?}", column_type);
+    } else if let (Some(unit), "DATE" | "DATETIME" | "TIMESTAMP") = (query.time_unit, column_type) {
+        push_time_since_epoch(l, row, idx, column_type, unit)?;
+    } else {
+        push_column_value_to_lua(l, row, idx, column_type)?;
+    }
+
+    if let Some(labels) = query.enum_as_index.get(column.name()) {
+        push_enum_index(l, column.name(), labels)?;
+    } else if let Some(coerce) = query.coerce.get(column.name()) {
+        coerce.apply(l, column.name(), query.json_ordered)?;
+    } else if query.parse_numeric_strings
+        && is_text_type(column_type)
+        && l.lua_type(-1) == LUA_TSTRING
+    {
+        if let Some(n) = parse_numeric(l.get_binary_string(-1).unwrap_or_default()) {
+            l.pop();
+            l.push_number(n);
         }
-        _ => {
-            bail!("unsupported column type: {}", column_type);
+    }
+
+    Ok(())
+}
+
+// replaces the label at the top of the stack with its 1 based position, like `column + 0` in sql
+// the empty string mysql stores for invalid values is 0
+fn push_enum_index(l: lua::State, column_name: &str, labels: &[Vec<u8>]) -> Result<()> {
+    if l.lua_type(-1) != LUA_TSTRING {
+        return Ok(());
+    }
+    let label = l.get_binary_string(-1).unwrap_or_default();
+    let index = match enum_index(labels, label) {
+        Some(index) => index,
+        None => bail!(
+            "'{}' is not one of the enum_as_index labels of column '{}'",
+            String::from_utf8_lossy(label),
+            column_name
+        ),
+    };
+    l.pop();
+    l.push_number(index);
+    Ok(())
+}
+
+fn enum_index(labels: &[Vec<u8>], label: &[u8]) -> Option<usize> {
+    match labels.iter().position(|l| l == label) {
+        Some(idx) => Some(idx + 1),
+        None if label.is_empty() => Some(0),
+        None => None,
+    }
+}
+
+// sqlx names types the way mysql does, mariadb and older servers report some of them differently
+pub fn type_name(name: &str) -> &str {
+    match name {
+        "NEWDECIMAL" | "NUMERIC" => "DECIMAL",
+        "VARSTRING" | "VAR_STRING" => "VARCHAR",
+        "STRING" => "CHAR",
+        "INTEGER" => "INT",
+        "BOOL" => "BOOLEAN",
+        name => name,
+    }
+}
+
+pub fn is_binary_type(column_type: &str) -> bool {
+    matches!(
+        column_type,
+        "BINARY" | "VARBINARY" | "TINYBLOB" | "BLOB" | "MEDIUMBLOB" | "LONGBLOB"
+    )
+}
+
+fn is_text_type(column_type: &str) -> bool {
+    matches!(
+        column_type,
+        "CHAR" | "VARCHAR" | "TEXT" | "TINYTEXT" | "MEDIUMTEXT" | "LONGTEXT"
+    )
+}
+
+// only plain decimal numbers like "42", "-3.14" or "0.5", things like "007", "1e5", " 42" or
+// integers too big to be exact as a lua number stay strings, they are most likely ids or codes
+fn parse_numeric(s: &[u8]) -> Option<f64> {
+    let digits = s.strip_prefix(b"-").unwrap_or(s);
+    let (int_part, frac_part) = match digits.iter().position(|b| *b == b'.') {
+        Some(dot) => (&digits[..dot], Some(&digits[dot + 1..])),
+        None => (digits, None),
+    };
+
+    if int_part.is_empty() || !int_part.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    if int_part.len() > 1 && int_part[0] == b'0' {
+        return None;
+    }
+    if let Some(frac_part) = frac_part {
+        if frac_part.is_empty() || !frac_part.iter().all(u8::is_ascii_digit) {
+            return None;
         }
     }
 
+    let n: f64 = std::str::from_utf8(s).ok()?.parse().ok()?;
+    if frac_part.is_none() && n.abs() > (1u64 << 53) as f64 {
+        return None;
+    }
+    Some(n)
+}
+
+// chrono writes fractional seconds with 3, 6 or 9 digits depending on the value, they are always
+// written with microsecond precision (what mysql stores at most) so they round trip, values without
+// fractional seconds are left as is. sqlx doesn't expose the precision the column declares
+pub fn pad_fraction(mut s: String) -> String {
+    // the seconds end 2 bytes after the last ':', a fraction (and " UTC" for TIMESTAMP) follows them
+    let seconds_end = match s.rfind(':') {
+        Some(colon) if colon + 3 <= s.len() => colon + 3,
+        _ => return s,
+    };
+    let fraction_end = s[seconds_end..]
+        .find(|c: char| c != '.' && !c.is_ascii_digit())
+        .map_or(s.len(), |i| seconds_end + i);
+    let mut fraction = s[seconds_end..fraction_end].to_string();
+    if fraction.is_empty() {
+        return s;
+    }
+
+    fraction.truncate(7);
+    while fraction.len() < 7 {
+        fraction.push('0');
+    }
+    s.replace_range(seconds_end..fraction_end, &fraction);
+    s
+}
+
+fn push_column_value_to_lua(
+    l: lua::State,
+    row: &MySqlRow,
+    idx: usize,
+    column_type: &str,
+) -> Result<()> {
+    match decode(row, idx, column_type)? {
+        ColumnValue::Null => l.push_nil(),
+        ColumnValue::Bool(b) => l.push_boolean(b),
+        ColumnValue::Int(n) => l.push_number(n),
+        ColumnValue::UInt(n) => l.push_number(n),
+        ColumnValue::Float(n) => l.push_number(n),
+        ColumnValue::Text(s) => l.push_string(&s),
+        ColumnValue::Bytes(bytes) => l.push_binary_string(bytes),
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Executor as _, Row as _};
+
+    use super::{decode_vector, enum_index, pad_fraction, parse_numeric, type_name};
+    use crate::test_db;
+
+    fn labels(labels: &[&str]) -> Vec<Vec<u8>> {
+        labels
+            .iter()
+            .map(|l| l.as_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn enum_labels_map_to_their_position() {
+        let labels = labels(&["active", "banned", "deleted"]);
+        assert_eq!(enum_index(&labels, b"active"), Some(1));
+        assert_eq!(enum_index(&labels, b"deleted"), Some(3));
+        // what mysql stores for invalid values
+        assert_eq!(enum_index(&labels, b""), Some(0));
+        assert_eq!(enum_index(&labels, b"unknown"), None);
+    }
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn enum_index_matches_the_server() {
+        test_db::run(async {
+            let mut conn = test_db::connect().await;
+            conn.execute(
+                "CREATE TEMPORARY TABLE goobie_enum (status ENUM('active', 'banned', 'deleted'))",
+            )
+            .await
+            .unwrap();
+            conn.execute("INSERT INTO goobie_enum VALUES ('banned')")
+                .await
+                .unwrap();
+
+            let row = sqlx::query("SELECT status, CAST(status AS UNSIGNED) FROM goobie_enum")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            let label = row.get::<String, _>(0);
+            let index = enum_index(&labels(&["active", "banned", "deleted"]), label.as_bytes());
+            assert_eq!(index, Some(2));
+            assert_eq!(index.map(|i| i as u64), Some(row.get::<u64, _>(1)));
+        });
+    }
+
+    #[test]
+    fn normalizes_type_names() {
+        assert_eq!(type_name("NEWDECIMAL"), "DECIMAL");
+        assert_eq!(type_name("VAR_STRING"), "VARCHAR");
+        assert_eq!(type_name("STRING"), "CHAR");
+        assert_eq!(type_name("BOOL"), "BOOLEAN");
+        assert_eq!(type_name("INT UNSIGNED"), "INT UNSIGNED");
+    }
+
+    #[test]
+    fn decodes_vectors() {
+        let mut bytes = 1.5f32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(-2.0f32).to_le_bytes());
+        assert_eq!(decode_vector(&bytes).unwrap(), [1.5, -2.0]);
+        assert!(decode_vector(&[]).unwrap().is_empty());
+        assert!(decode_vector(&[0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn parses_only_plain_numbers() {
+        assert_eq!(parse_numeric(b"42"), Some(42.0));
+        assert_eq!(parse_numeric(b"-3.25"), Some(-3.25));
+        assert_eq!(parse_numeric(b"0.5"), Some(0.5));
+        for s in [
+            &b"007"[..],
+            b"1e5",
+            b" 42",
+            b"1.",
+            b".5",
+            b"",
+            b"-",
+            b"76561198000000001",
+        ] {
+            assert_eq!(parse_numeric(s), None);
+        }
+    }
+
+    #[test]
+    fn fractions_are_written_as_micros() {
+        assert_eq!(
+            pad_fraction("2024-01-01 12:00:00.12".into()),
+            "2024-01-01 12:00:00.120000"
+        );
+        assert_eq!(pad_fraction("12:00:00.123456789".into()), "12:00:00.123456");
+        assert_eq!(
+            pad_fraction("2024-01-01 12:00:00.120 UTC".into()),
+            "2024-01-01 12:00:00.120000 UTC"
+        );
+    }
+
+    #[test]
+    fn no_fraction_is_left_as_is() {
+        assert_eq!(pad_fraction("12:00:00".into()), "12:00:00");
+        assert_eq!(
+            pad_fraction("2024-01-01 12:00:00 UTC".into()),
+            "2024-01-01 12:00:00 UTC"
+        );
+    }
+}
@@ -1,15 +1,67 @@
+use gmod::*;
 use sqlx::mysql::{MySqlQueryResult, MySqlRow};
 
+use crate::cstr_from_args;
+
 #[derive(Debug)]
 pub enum QueryType {
     Execute,
     FetchOne,
     FetchAll,
+    FetchValue,
+    FetchRow,
+    ExecuteMany,
+    Pipeline,
 }
 
 #[derive(Debug)]
 pub enum QueryResult {
     Execute(MySqlQueryResult),
+    Inserted(MySqlQueryResult, Option<MySqlRow>),
     Row(Option<MySqlRow>),
     Rows(Vec<MySqlRow>),
+    Page(Vec<MySqlRow>, i64),
+    Pipeline(Vec<QueryResult>),
+    // one Rows or Execute per statement
+    Many(Vec<QueryResult>),
+    // rows handled by conn:FetchToFile and conn:FetchChunked, or affected by conn:Upsert
+    Count(u64),
+}
+
+const ROW_META_NAME: LuaCStr = cstr_from_args!(crate::GLOBAL_TABLE_NAME, "_row");
+
+pub fn init(l: lua::State) {
+    l.new_metatable(ROW_META_NAME);
+    {
+        l.push_function(__index);
+        l.set_field(-2, c"__index");
+    }
+    l.pop();
+}
+
+// row table has to be at the top of the stack
+pub fn set_case_insensitive(l: lua::State) {
+    l.get_metatable_name(ROW_META_NAME);
+    unsafe { l.set_metatable(-2) };
+}
+
+// only runs when the exact key isn't in the row, looks for a column with the same name in any case
+#[lua_function]
+fn __index(l: lua::State) -> i32 {
+    if l.lua_type(2) != LUA_TSTRING {
+        l.push_nil();
+        return 1;
+    }
+    let key = l.get_string_unchecked(2).to_lowercase();
+
+    l.push_nil();
+    while l.next(1) != 0 {
+        if l.lua_type(-2) == LUA_TSTRING && l.get_string_unchecked(-2).to_lowercase() == key {
+            return 1;
+        }
+        l.pop();
+    }
+
+    l.push_nil();
+    1
 }
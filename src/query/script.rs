@@ -0,0 +1,92 @@
+use anyhow::{bail, Result};
+
+use super::placeholder::{skip_line, skip_literal};
+
+// splits a .sql script into statements, DELIMITER lines change what ends a statement the same way
+// they do in the mysql client, so stored procedures can be defined
+pub fn split(script: &str) -> Result<Vec<String>> {
+    let bytes = script.as_bytes();
+    let mut delimiter = ";".to_string();
+    let mut statements = Vec::new();
+    let mut start = 0;
+    // nothing but whitespace and comments since the last statement
+    let mut at_start = true;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        if at_start && b.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if at_start && is_delimiter_command(&bytes[i..]) {
+            let line_end = skip_line(bytes, i);
+            delimiter = match script[i + 9..line_end].split_whitespace().next() {
+                Some(delimiter) => delimiter.to_string(),
+                None => bail!("DELIMITER needs a delimiter"),
+            };
+            i = line_end;
+            start = i;
+            continue;
+        }
+
+        if let Some(end) = skip_literal(bytes, i) {
+            if matches!(b, b'\'' | b'"' | b'`') {
+                at_start = false;
+            }
+            i = end;
+            continue;
+        }
+
+        if bytes[i..].starts_with(delimiter.as_bytes()) {
+            if !at_start {
+                statements.push(script[start..i].trim().to_string());
+            }
+            i += delimiter.len();
+            start = i;
+            at_start = true;
+            continue;
+        }
+
+        at_start = false;
+        i += 1;
+    }
+
+    if !at_start {
+        statements.push(script[start..].trim().to_string());
+    }
+
+    Ok(statements)
+}
+
+fn is_delimiter_command(bytes: &[u8]) -> bool {
+    bytes.len() > 9
+        && bytes[..9].eq_ignore_ascii_case(b"delimiter")
+        && bytes[9].is_ascii_whitespace()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split;
+
+    #[test]
+    fn splits_on_semicolons_outside_literals() {
+        assert_eq!(
+            split("SELECT 1; SELECT ';' ;\n\nSELECT `a;b` FROM t /* ; */").unwrap(),
+            ["SELECT 1", "SELECT ';'", "SELECT `a;b` FROM t /* ; */"]
+        );
+        assert!(split("  \n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn follows_delimiter_lines() {
+        let script =
+            "DELIMITER $$\nCREATE PROCEDURE p() BEGIN SELECT 1; END$$\nDELIMITER ;\nSELECT 2;";
+        assert_eq!(
+            split(script).unwrap(),
+            ["CREATE PROCEDURE p() BEGIN SELECT 1; END", "SELECT 2"]
+        );
+        assert!(split("DELIMITER \nSELECT 1").is_err());
+    }
+}
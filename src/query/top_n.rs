@@ -0,0 +1,161 @@
+use std::cmp::Ordering;
+
+use anyhow::{bail, Result};
+use gmod::*;
+use sqlx::{
+    mysql::MySqlRow,
+    types::{
+        chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc},
+        Decimal,
+    },
+    Column as _, Row as _, TypeInfo as _, ValueRef as _,
+};
+
+use super::process::type_name;
+
+// { column = "score", n = 10, desc = true }, keeps the n rows with the smallest (or largest) values
+#[derive(Debug)]
+pub struct TopN {
+    column: String,
+    n: usize,
+    desc: bool,
+}
+
+// NULLs sort first, same as mysql
+#[derive(Debug, PartialEq, PartialOrd)]
+enum SortKey {
+    Null,
+    Int(i128),
+    Float(f64),
+    Decimal(Decimal),
+    Bytes(Vec<u8>),
+}
+
+impl TopN {
+    // table is at the top of the stack
+    pub fn parse(l: lua::State) -> Result<Self> {
+        if !l.get_field_type_or_nil(-1, c"column", LUA_TSTRING)? {
+            bail!("top_n needs a column");
+        }
+        let column = l.get_string_unchecked(-1).into_owned();
+        l.pop();
+
+        if !l.get_field_type_or_nil(-1, c"n", LUA_TNUMBER)? {
+            bail!("top_n needs n");
+        }
+        let n = l.to_number(-1);
+        l.pop();
+        if n < 1.0 || n.fract() != 0.0 {
+            bail!("top_n n must be a positive integer");
+        }
+
+        let mut desc = false;
+        if l.get_field_type_or_nil(-1, c"desc", LUA_TBOOLEAN)? {
+            desc = l.get_boolean(-1);
+            l.pop();
+        }
+
+        Ok(TopN {
+            column,
+            n: n as usize,
+            desc,
+        })
+    }
+
+    // rows with the same value keep the order the server sent them in
+    pub fn apply(&self, rows: Vec<MySqlRow>) -> Result<Vec<MySqlRow>> {
+        if rows.len() <= self.n {
+            return Ok(rows);
+        }
+
+        let idx = match rows[0]
+            .columns()
+            .iter()
+            .position(|c| c.name() == self.column)
+        {
+            Some(idx) => idx,
+            None => bail!("top_n column '{}' is not in the result", self.column),
+        };
+
+        let mut keyed = rows
+            .into_iter()
+            .map(|row| Ok((sort_key(&row, idx)?, row)))
+            .collect::<Result<Vec<_>>>()?;
+        keyed.sort_by(|(a, _), (b, _)| {
+            let ord = a.partial_cmp(b).unwrap_or(Ordering::Equal);
+            if self.desc {
+                ord.reverse()
+            } else {
+                ord
+            }
+        });
+        keyed.truncate(self.n);
+
+        Ok(keyed.into_iter().map(|(_, row)| row).collect())
+    }
+}
+
+// temporal values are compared by their text form, which sorts the same way
+fn sort_key(row: &MySqlRow, idx: usize) -> Result<SortKey> {
+    if row.try_get_raw(idx)?.is_null() {
+        return Ok(SortKey::Null);
+    }
+
+    let column_type = type_name(row.column(idx).type_info().name());
+    let key = match column_type {
+        "BOOLEAN" => SortKey::Int(row.try_get::<bool, _>(idx)? as i128),
+        "TINYINT" | "SMALLINT" | "INT" | "MEDIUMINT" | "BIGINT" => {
+            SortKey::Int(row.try_get_unchecked::<i64, _>(idx)? as i128)
+        }
+        "TINYINT UNSIGNED" | "SMALLINT UNSIGNED" | "INT UNSIGNED" | "MEDIUMINT UNSIGNED"
+        | "BIGINT UNSIGNED" | "YEAR" => SortKey::Int(row.try_get_unchecked::<u64, _>(idx)? as i128),
+        "FLOAT" => SortKey::Float(row.try_get::<f32, _>(idx)? as f64),
+        "DOUBLE" => SortKey::Float(row.try_get::<f64, _>(idx)?),
+        "DECIMAL" => SortKey::Decimal(row.try_get::<Decimal, _>(idx)?),
+        "TIME" => SortKey::Bytes(
+            row.try_get::<NaiveTime, _>(idx)?
+                .to_string()
+                .into_bytes(),
+        ),
+        "DATE" => SortKey::Bytes(
+            row.try_get::<NaiveDate, _>(idx)?
+                .to_string()
+                .into_bytes(),
+        ),
+        "DATETIME" => SortKey::Bytes(
+            row.try_get::<NaiveDateTime, _>(idx)?
+                .to_string()
+                .into_bytes(),
+        ),
+        "TIMESTAMP" => SortKey::Bytes(
+            row.try_get::<DateTime<Utc>, _>(idx)?
+                .to_string()
+                .into_bytes(),
+        ),
+        _ => SortKey::Bytes(row.try_get_unchecked::<&[u8], _>(idx)?.to_vec()),
+    };
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SortKey;
+
+    #[test]
+    fn null_sorts_first() {
+        assert!(SortKey::Null < SortKey::Int(i128::MIN));
+        assert!(SortKey::Null < SortKey::Bytes(Vec::new()));
+    }
+
+    #[test]
+    fn values_of_a_column_compare_by_value() {
+        assert!(SortKey::Int(2) < SortKey::Int(10));
+        assert!(SortKey::Float(-1.5) < SortKey::Float(0.25));
+        assert!(
+            SortKey::Decimal("9.99".parse().unwrap()) < SortKey::Decimal("10.01".parse().unwrap())
+        );
+        // text is compared byte by byte, dates in their text form sort by time
+        assert!(SortKey::Bytes(b"10".to_vec()) < SortKey::Bytes(b"9".to_vec()));
+        assert!(SortKey::Bytes(b"2023-12-31".to_vec()) < SortKey::Bytes(b"2024-01-01".to_vec()));
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{bail, Result};
+use serde::{ser::Error as _, ser::SerializeMap as _, Serialize, Serializer};
+use serde_json::{Map, Value};
+use sqlx::{
+    mysql::MySqlRow,
+    types::{
+        chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc},
+        Decimal,
+    },
+    Column as _, Row as _, TypeInfo as _, ValueRef as _,
+};
+
+use super::process::{decode_vector, is_binary_type, pad_fraction, type_name};
+
+// a column decoded from a row, rows pushed to lua and rows written out (FetchToFile, msgpack) all go
+// through decode so they can't disagree on how a type is read
+pub enum ColumnValue<'r> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    // DECIMAL, dates and times, already formatted
+    Text(String),
+    // strings, blobs and VECTOR, as sent by the server
+    Bytes(&'r [u8]),
+}
+
+pub fn decode<'r>(row: &'r MySqlRow, idx: usize, column_type: &str) -> Result<ColumnValue<'r>> {
+    if row.try_get_raw(idx)?.is_null() {
+        return Ok(ColumnValue::Null);
+    }
+
+    let value = match column_type {
+        "NULL" => ColumnValue::Null,
+        "BOOLEAN" => ColumnValue::Bool(row.try_get(idx)?),
+        "TINYINT" => ColumnValue::Int(row.try_get::<i8, _>(idx)?.into()),
+        "SMALLINT" => ColumnValue::Int(row.try_get::<i16, _>(idx)?.into()),
+        "INT" | "MEDIUMINT" => ColumnValue::Int(row.try_get::<i32, _>(idx)?.into()),
+        "BIGINT" => ColumnValue::Int(row.try_get(idx)?),
+        "TINYINT UNSIGNED" => ColumnValue::UInt(row.try_get::<u8, _>(idx)?.into()),
+        "SMALLINT UNSIGNED" => ColumnValue::UInt(row.try_get::<u16, _>(idx)?.into()),
+        "INT UNSIGNED" | "MEDIUMINT UNSIGNED" => {
+            ColumnValue::UInt(row.try_get::<u32, _>(idx)?.into())
+        }
+        // sqlx doesn't consider YEAR compatible with any integer, it's sent as one though
+        "YEAR" => ColumnValue::UInt(row.try_get_unchecked::<u16, _>(idx)?.into()),
+        "BIGINT UNSIGNED" => ColumnValue::UInt(row.try_get(idx)?),
+        "FLOAT" => ColumnValue::Float(row.try_get::<f32, _>(idx)?.into()),
+        "DOUBLE" => ColumnValue::Float(row.try_get(idx)?),
+        "DECIMAL" => ColumnValue::Text(row.try_get::<Decimal, _>(idx)?.to_string()),
+        "TIME" => ColumnValue::Text(pad_fraction(row.try_get::<NaiveTime, _>(idx)?.to_string())),
+        "DATE" => ColumnValue::Text(row.try_get::<NaiveDate, _>(idx)?.to_string()),
+        "DATETIME" => ColumnValue::Text(pad_fraction(
+            row.try_get::<NaiveDateTime, _>(idx)?.to_string(),
+        )),
+        "TIMESTAMP" => ColumnValue::Text(pad_fraction(
+            row.try_get::<DateTime<Utc>, _>(idx)?.to_string(),
+        )),
+        // sqlx has no type for VECTOR, it's sent as raw bytes
+        "BINARY" | "VARBINARY" | "TINYBLOB" | "BLOB" | "MEDIUMBLOB" | "LONGBLOB" | "CHAR"
+        | "VARCHAR" | "TEXT" | "TINYTEXT" | "MEDIUMTEXT" | "LONGTEXT" | "JSON" | "ENUM" | "SET"
+        | "VECTOR" => ColumnValue::Bytes(row.try_get_unchecked(idx)?),
+        _ => bail!("unsupported column type: {}", column_type),
+    };
+    Ok(value)
+}
+
+// rows as json values, for output that never goes through lua tables (FetchToFile)
+
+pub fn row_object(row: &MySqlRow) -> Result<Value> {
+    let mut object = Map::with_capacity(row.len());
+    for (idx, column) in row.columns().iter().enumerate() {
+        object.insert(column.name().to_string(), column_value(row, idx)?);
+    }
+    Ok(Value::Object(object))
+}
+
+// json has no bytes, so bytes that aren't valid utf8 get replaced and VECTOR becomes an array of floats
+pub fn column_value(row: &MySqlRow, idx: usize) -> Result<Value> {
+    let column_type = type_name(row.column(idx).type_info().name());
+    let value = match decode(row, idx, column_type)? {
+        ColumnValue::Null => Value::Null,
+        ColumnValue::Bool(b) => b.into(),
+        ColumnValue::Int(n) => n.into(),
+        ColumnValue::UInt(n) => n.into(),
+        ColumnValue::Float(n) => n.into(),
+        ColumnValue::Text(s) => s.into(),
+        ColumnValue::Bytes(bytes) if column_type == "VECTOR" => decode_vector(bytes)?.into(),
+        ColumnValue::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned().into(),
+    };
+    Ok(value)
+}
+
+// rows for format = "msgpack", unlike json it has a binary type so bytes are kept as they are
+pub struct MsgpackRow<'r>(pub &'r MySqlRow);
+
+impl Serialize for MsgpackRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let row = self.0;
+        let mut map = serializer.serialize_map(Some(row.len()))?;
+        for (idx, column) in row.columns().iter().enumerate() {
+            let column_type = type_name(column.type_info().name());
+            map.serialize_key(column.name())?;
+            match decode(row, idx, column_type).map_err(S::Error::custom)? {
+                ColumnValue::Null => map.serialize_value(&())?,
+                ColumnValue::Bool(b) => map.serialize_value(&b)?,
+                ColumnValue::Int(n) => map.serialize_value(&n)?,
+                ColumnValue::UInt(n) => map.serialize_value(&n)?,
+                ColumnValue::Float(n) => map.serialize_value(&n)?,
+                ColumnValue::Text(s) => map.serialize_value(&s)?,
+                // text that isn't valid utf8 (e.g. a latin1 column) can't be a msgpack str either
+                ColumnValue::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                    Ok(s) if !is_binary_type(column_type) && column_type != "VECTOR" => {
+                        map.serialize_value(s)?
+                    }
+                    _ => map.serialize_value(&Bin(bytes))?,
+                },
+            }
+        }
+        map.end()
+    }
+}
+
+struct Bin<'a>(&'a [u8]);
+
+impl Serialize for Bin<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor as _;
+
+    use super::{decode, ColumnValue};
+    use crate::test_db;
+
+    #[test]
+    #[ignore = "needs a server, see test_db.rs"]
+    fn fractional_seconds_round_trip() {
+        test_db::run(async {
+            let mut conn = test_db::connect().await;
+            let row = conn
+                .fetch_one(
+                    "SELECT CAST('2024-01-01 12:00:00.123456' AS DATETIME(6)),
+                    CAST('2024-01-01 12:00:00.5' AS DATETIME(3)),
+                    CAST('2024-01-01 12:00:00' AS DATETIME(6)),
+                    CAST('12:00:00.25' AS TIME(2))",
+                )
+                .await
+                .unwrap();
+
+            let text = |idx, column_type| match decode(&row, idx, column_type).unwrap() {
+                ColumnValue::Text(s) => s,
+                _ => panic!("column {} isn't text", idx),
+            };
+            assert_eq!(text(0, "DATETIME"), "2024-01-01 12:00:00.123456");
+            assert_eq!(text(1, "DATETIME"), "2024-01-01 12:00:00.500000");
+            assert_eq!(text(2, "DATETIME"), "2024-01-01 12:00:00");
+            assert_eq!(text(3, "TIME"), "12:00:00.250000");
+        });
+    }
+}
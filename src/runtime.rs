@@ -1,17 +1,37 @@
-use std::{mem::MaybeUninit, sync::mpsc, time};
+use std::{
+    mem::MaybeUninit,
+    sync::{mpsc, Arc, Mutex},
+    time,
+};
 
+use anyhow::Result;
 use gmod::{lua, task_queue::run_callbacks};
-use tokio::runtime::{Builder, Runtime};
-use tokio_util::task::TaskTracker;
+use tokio::{
+    runtime::{Builder, Runtime},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::{print_goobie, TASKS_WAITING_TIMEOUT};
+use crate::{error::KindError, print_goobie, QUERIES_CANCEL_TIMEOUT, TASKS_WAITING_TIMEOUT};
 
 static mut RUN_TIME: MaybeUninit<Runtime> = MaybeUninit::uninit();
 static mut TASK_TRACKER: MaybeUninit<TaskTracker> = MaybeUninit::uninit();
+static mut SHUTDOWN_TOKEN: MaybeUninit<CancellationToken> = MaybeUninit::uninit();
 
-pub(super) fn load(worker_threads: u16) {
+// shared by all connections, None when queries are not limited
+static QUERY_LIMIT: Mutex<Option<Arc<Semaphore>>> = Mutex::new(None);
+
+pub(super) fn load(worker_threads: u16, max_concurrent_queries: u32) {
     print_goobie!("Using {worker_threads} worker threads");
 
+    let query_limit = if max_concurrent_queries > 0 {
+        print_goobie!("Limiting to {max_concurrent_queries} concurrent queries");
+        Some(Arc::new(Semaphore::new(max_concurrent_queries as usize)))
+    } else {
+        None
+    };
+    *QUERY_LIMIT.lock().unwrap() = query_limit;
+
     let run_time = Builder::new_multi_thread()
         .worker_threads(worker_threads as usize)
         .max_blocking_threads(1)
@@ -24,6 +44,7 @@ pub(super) fn load(worker_threads: u16) {
     unsafe {
         RUN_TIME = MaybeUninit::new(run_time);
         TASK_TRACKER = MaybeUninit::new(task_tracker);
+        SHUTDOWN_TOKEN = MaybeUninit::new(CancellationToken::new());
     }
 }
 
@@ -33,14 +54,30 @@ pub(super) fn unload() {
     let task_tracker = unsafe { TASK_TRACKER.assume_init_read() };
     task_tracker.close();
 
+    // not read out of the static, pending futures still hold a reference to it when the runtime drops them
+    let shutdown_token = unsafe { SHUTDOWN_TOKEN.assume_init_ref() };
+
     if !task_tracker.is_empty() {
         print_goobie!(
             "Waiting up to {} seconds for {} pending tasks to complete...",
-            TASKS_WAITING_TIMEOUT.as_secs(),
+            (QUERIES_CANCEL_TIMEOUT + TASKS_WAITING_TIMEOUT).as_secs(),
             task_tracker.len()
         );
 
         run_time.block_on(async {
+            // give queries a chance to finish normally, then cancel whatever is still running so a stuck
+            // query can't hold the whole shutdown, the rest of the timeout is only spent on cleanup
+            // (disconnecting, rolling back...)
+            let finished = tokio::select! {
+                _ = task_tracker.wait() => true,
+                _ = tokio::time::sleep(QUERIES_CANCEL_TIMEOUT) => false,
+            };
+
+            if !finished {
+                print_goobie!("Cancelling {} pending tasks...", task_tracker.len());
+                shutdown_token.cancel();
+            }
+
             tokio::select! {
                 _ = task_tracker.wait() => {
                     print_goobie!("All pending tasks have completed!");
@@ -51,6 +88,8 @@ pub(super) fn unload() {
             }
         });
     }
+
+    shutdown_token.cancel();
 }
 
 fn read<'a>() -> &'a Runtime {
@@ -61,6 +100,53 @@ fn read_tracker<'a>() -> &'a TaskTracker {
     unsafe { TASK_TRACKER.assume_init_ref() }
 }
 
+// worker saturation, a worker is busy while it isn't parked waiting for work
+#[cfg(feature = "runtime-metrics")]
+pub struct Metrics {
+    pub workers: usize,
+    pub busy_workers: usize,
+    pub alive_tasks: usize,
+    pub pending_tasks: usize,
+    pub global_queue_depth: usize,
+}
+
+#[cfg(feature = "runtime-metrics")]
+pub fn metrics() -> Metrics {
+    let metrics = read().metrics();
+    let workers = metrics.num_workers();
+    // odd means the worker is parked right now
+    let busy_workers = (0..workers)
+        .filter(|&worker| metrics.worker_park_unpark_count(worker) % 2 == 0)
+        .count();
+    Metrics {
+        workers,
+        busy_workers,
+        alive_tasks: metrics.num_alive_tasks(),
+        pending_tasks: read_tracker().len(),
+        global_queue_depth: metrics.global_queue_depth(),
+    }
+}
+
+// the runtime is gone after unload, anything that needs it has to fail instead of silently doing nothing
+pub fn ensure_running() -> Result<()> {
+    if crate::is_gmod_closed() {
+        return Err(KindError::new("runtime_shutdown", "the runtime has been shut down").into());
+    }
+    Ok(())
+}
+
+// waits until there is room for another query, queries queue up instead of failing when the limit is hit
+pub async fn acquire_query_permit() -> Option<OwnedSemaphorePermit> {
+    let query_limit = QUERY_LIMIT.lock().unwrap().clone()?;
+    query_limit.acquire_owned().await.ok()
+}
+
+// resolves once the runtime starts shutting down
+pub async fn shutdown_signal() {
+    let shutdown_token = unsafe { SHUTDOWN_TOKEN.assume_init_ref() };
+    shutdown_token.cancelled().await
+}
+
 pub fn run_async<F>(fut: F) -> tokio::task::JoinHandle<F::Output>
 where
     F: std::future::Future + Send + 'static,
@@ -0,0 +1,40 @@
+use std::{sync::Mutex, time::Instant};
+
+use anyhow::Result;
+use gmod::*;
+
+use crate::{print_goobie, SHUTDOWN_HOOKS_TIMEOUT};
+
+static HOOKS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+#[lua_function]
+pub fn on_shutdown(l: lua::State) -> Result<i32> {
+    l.check_function(1)?;
+    l.push_value(1);
+    HOOKS.lock().unwrap().push(l.reference());
+    Ok(0)
+}
+
+// runs before the runtime starts unloading, so hooks can still run queries, sync ones finish right away and
+// async ones are waited on like any other pending query
+pub fn run_hooks(l: lua::State) {
+    let hooks = std::mem::take(&mut *HOOKS.lock().unwrap());
+    let started = Instant::now();
+
+    for (idx, hook) in hooks.iter().enumerate() {
+        // a hook can't be interrupted, the budget only stops the ones that didn't get to run yet
+        if started.elapsed() > SHUTDOWN_HOOKS_TIMEOUT {
+            print_goobie!(
+                "Shutdown hooks took longer than {} seconds, skipping {} remaining",
+                SHUTDOWN_HOOKS_TIMEOUT.as_secs(),
+                hooks.len() - idx
+            );
+            break;
+        }
+        l.pcall_ignore_function_ref(*hook, 0, 0);
+    }
+
+    for hook in hooks {
+        l.dereference(hook);
+    }
+}
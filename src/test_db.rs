@@ -0,0 +1,60 @@
+// tests that need a server are ignored by default, point GOOBIE_MYSQL_TEST_URL at a database they can
+// create tables in and run them with `cargo test -- --ignored`
+use std::{future::Future, sync::Once};
+
+use sqlx::{mysql::MySqlConnectOptions, Connection as _, MySqlConnection};
+
+use crate::{error::KindError, run_async, runtime};
+
+pub fn url() -> String {
+    std::env::var("GOOBIE_MYSQL_TEST_URL").expect("GOOBIE_MYSQL_TEST_URL is not set")
+}
+
+pub fn options() -> MySqlConnectOptions {
+    url()
+        .parse()
+        .expect("GOOBIE_MYSQL_TEST_URL is not a valid mysql url")
+}
+
+pub fn database() -> String {
+    options()
+        .get_database()
+        .expect("GOOBIE_MYSQL_TEST_URL has no database")
+        .to_string()
+}
+
+// same server and credentials, connecting without picking a database
+pub fn url_without_database() -> String {
+    let url = url();
+    let path_start = url.find("://").map_or(0, |i| i + 3);
+    match url[path_start..].find('/') {
+        Some(i) => url[..path_start + i].to_string(),
+        None => url,
+    }
+}
+
+pub async fn connect() -> MySqlConnection {
+    MySqlConnection::connect_with(&options())
+        .await
+        .expect("failed to connect to GOOBIE_MYSQL_TEST_URL")
+}
+
+pub fn kind(e: &anyhow::Error) -> Option<&'static str> {
+    e.downcast_ref::<KindError>().map(|e| e.kind)
+}
+
+// queries wait on the runtime's shutdown signal, so they have to run on it like they do in game
+pub fn run<F>(fut: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    static LOAD: Once = Once::new();
+    LOAD.call_once(|| runtime::load(2, 0));
+
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(run_async(fut))
+        .unwrap()
+}